@@ -16,7 +16,25 @@ fn main() {
     let bindings = builder
         .whitelist_function("vips_thumbnail_buffer")
         .whitelist_function("vips_jpegsave_buffer")
+        .whitelist_function("vips_pngsave_buffer")
+        .whitelist_function("vips_webpsave_buffer")
+        .whitelist_function("vips_heifsave_buffer")
+        .whitelist_function("vips_image_new_from_buffer")
+        .whitelist_function("vips_image_get_width")
+        .whitelist_function("vips_image_get_height")
+        .whitelist_function("vips_image_get_bands")
+        .whitelist_function("vips_image_get_n_pages")
+        .whitelist_function("vips_image_get_interpretation")
+        .whitelist_function("vips_image_hasalpha")
+        .whitelist_function("vips_interpretation_get_type")
+        .whitelist_function("vips_enum_nick")
         .whitelist_function("vips_error_buffer")
+        .whitelist_function("vips_version")
+        .whitelist_function("g_object_unref")
+        .whitelist_function("g_free")
+        .whitelist_type("VipsInteresting")
+        .whitelist_type("VipsSize")
+        .whitelist_type("VipsForeignHeifCompression")
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
         .generate()
         .expect("generate bindings");
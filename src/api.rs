@@ -1,17 +1,43 @@
-use super::service;
-use futures::stream::TryStreamExt;
-use hyper::http::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+pub(crate) mod jobs;
+
+use super::auth::{self, Scope};
+use super::config::CONFIG;
+use super::key::{IdScheme, ImageKey};
+use super::range::{self, Ranges};
+use super::storage::{self, Storage};
+use super::webhook::{self, Event, EventKind};
+use super::{libvips, service};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use hyper::http::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+    WWW_AUTHENTICATE,
+};
 use hyper::{Body, Method, Request, Response, StatusCode};
 use multipart_async::server::Multipart;
+use percent_encoding::percent_decode_str;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::env;
 use std::error::Error as StdError;
-use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{fs::File, prelude::*};
+
+/// Number of images listed per page by default.
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// Upper bound for the number of images listed per page.
+const MAX_LIST_LIMIT: usize = 1000;
+
+static UPLOAD_SEQ: AtomicUsize = AtomicUsize::new(0);
 
 lazy_static! {
-    static ref GET_IMG: Regex = Regex::new(r"^/images/[^/]+$").expect("regexp");
-    static ref IMG_FOLDER: String = env::var("IMG_FOLDER").unwrap_or("images".to_string());
+    static ref GET_IMG: Regex = Regex::new(r"^/images/([^/]+)$").expect("regexp");
+    static ref GET_ORIGINAL: Regex = Regex::new(r"^/images/([^/]+)/original$").expect("regexp");
+    static ref GET_META: Regex = Regex::new(r"^/images/([^/]+)/meta$").expect("regexp");
+    static ref GET_PRESET: Regex = Regex::new(r"^/images/([^/]+)/([^/]+)$").expect("regexp");
+    static ref STORAGE: Box<dyn Storage> = storage::new(&CONFIG.storage);
 }
 
 pub(crate) async fn svc(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
@@ -26,36 +52,117 @@ pub(crate) async fn svc(req: Request<Body>) -> Result<Response<Body>, hyper::Err
 }
 
 async fn route(req: Request<Body>) -> Result<Response<Body>, Error> {
+    if let Some(scope) = required_scope(req.method(), req.uri().path()) {
+        auth::authorize(&CONFIG.auth, req.headers(), scope)?;
+    }
+
     match (req.method(), req.uri().path()) {
         (&Method::POST, "/images") => store_img(req).await,
-        _ if GET_IMG.is_match(req.uri().path()) => get_img(req).await,
+        (&Method::GET, "/images") => list_img(req).await,
+        (&Method::POST, "/images:batchDelete") => batch_delete_img(req).await,
+        (&Method::POST, "/jobs") => jobs::submit(req).await,
+        (&Method::GET, path) if jobs::GET_JOB.is_match(path) => jobs::get(req).await,
+        (&Method::DELETE, path) if GET_IMG.is_match(path) => delete_img(req).await,
+        (&Method::GET, path) | (&Method::HEAD, path) if GET_IMG.is_match(path) => {
+            get_img(req).await
+        }
+        (&Method::GET, path) | (&Method::HEAD, path) if GET_ORIGINAL.is_match(path) => {
+            get_original(req).await
+        }
+        (&Method::GET, path) | (&Method::HEAD, path) if GET_META.is_match(path) => {
+            get_meta(req).await
+        }
+        (&Method::GET, path) | (&Method::HEAD, path) if GET_PRESET.is_match(path) => {
+            get_preset(req).await
+        }
+        (_, "/images") => method_not_allowed("GET, POST"),
+        (_, "/images:batchDelete") | (_, "/jobs") => method_not_allowed("POST"),
+        (_, path) if jobs::GET_JOB.is_match(path) => method_not_allowed("GET"),
+        (_, path) if GET_IMG.is_match(path) => method_not_allowed("GET, HEAD, DELETE"),
+        // Covers originals and metadata too.
+        (_, path) if GET_PRESET.is_match(path) => method_not_allowed("GET, HEAD"),
         _ => Err(Error::not_found("unknown route".to_string())),
     }
 }
 
+/// Answers requests to a known path with a method it doesn't support.
+fn method_not_allowed(allow: &'static str) -> Result<Response<Body>, Error> {
+    let cause = format!("allowed methods: {}", allow);
+    let err = Error::new(StatusCode::METHOD_NOT_ALLOWED, cause);
+    let mut resp = ErrorResponseBody::from_error(err).into_response();
+    resp.headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(allow));
+    Ok(resp)
+}
+
+/// Scope of the API key the request needs, `None` if it may be made without a key.
+fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    match *method {
+        Method::GET | Method::HEAD if path.starts_with("/images/") && CONFIG.auth.public_reads => {
+            None
+        }
+        Method::GET | Method::HEAD => Some(Scope::Read),
+        Method::DELETE => Some(Scope::Delete),
+        Method::POST if path == "/images:batchDelete" => Some(Scope::Delete),
+        _ => Some(Scope::Write),
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum ImageData {
     URI(String),
     Base64(String),
     Bytes(Vec<u8>),
+    /// File of a multipart form.
+    #[serde(skip)]
+    Upload(Upload),
 }
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct ImageRequest {
-    pub(crate) filename: String,
+    /// Name to store the image under, the server assigns one if it's missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) filename: Option<String>,
     pub(crate) data: ImageData,
+    /// How to name the image if `filename` is missing.
+    #[serde(default)]
+    pub(crate) id: IdScheme,
+    #[serde(default)]
+    pub(crate) on_conflict: service::ConflictPolicy,
+    #[serde(flatten)]
+    pub(crate) thumbnail: service::ThumbnailOptions,
+    /// Names of configured presets to render in addition to the thumbnail.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) presets: Vec<String>,
 }
 
 impl ImageRequest {
+    fn check(&self) -> Result<(), Error> {
+        if let Some(name) = &self.filename {
+            service::image_key(name)?;
+        }
+
+        match self.presets.iter().find(|p| CONFIG.preset(p).is_none()) {
+            Some(p) => Err(Error::bad_request(format!("presets: unknown preset {}", p))),
+            None => Ok(()),
+        }
+    }
+
     async fn into_image(self) -> Result<service::Image, Error> {
-        let img = match self.data {
-            ImageData::URI(u) => service::Image::from_remote_source(self.filename, u).await?,
-            ImageData::Base64(s) => service::Image::from_base64(self.filename, s).await?,
-            ImageData::Bytes(b) => service::Image::new(self.filename, b),
+        let data = match self.data {
+            ImageData::URI(u) => service::fetch_remote(u).await?,
+            ImageData::Base64(s) => service::decode_base64(s).await?,
+            ImageData::Bytes(b) => b,
+            ImageData::Upload(u) => u.read().await?,
+        };
+
+        let key = match &self.filename {
+            Some(name) => service::image_key(name)?,
+            None => ImageKey::generate(self.id, &data, self.thumbnail.format.extension()),
         };
 
-        Ok(img)
+        Ok(service::Image::new(key, data))
     }
 }
 
@@ -64,21 +171,41 @@ pub(crate) struct StoreImgRequestBody(pub(crate) Vec<ImageRequest>);
 
 impl StoreImgRequestBody {
     async fn from_json_request(req: Request<Body>) -> Result<Self, Error> {
-        let body = req.into_body().try_concat().await.or_internal_err()?;
-        let req = serde_json::from_slice(&body.to_vec()).or_bad_request("invalid json")?;
+        let body = read_body(req).await?;
+        let req: Self = serde_json::from_slice(&body).or_bad_request("invalid json")?;
+        if req.0.len() > CONFIG.uploads.max_files {
+            return Err(Error::payload_too_large());
+        }
+
         Ok(req)
     }
 
-    async fn from_multipart_request(req: Request<Body>) -> Result<Self, Error> {
+    /// Spools the files to disk one by one. They are read back while they're stored, so up to
+    /// `batch_concurrency` of them are held in memory at once.
+    async fn from_multipart_request(req: Request<Body>, query: &StoreQuery) -> Result<Self, Error> {
+        check_content_length(&req)?;
         let mut multipart = Multipart::try_from_request(req)
             .map_err(|_| Error::bad_request("invalid multipart form data".to_string()))?;
 
+        let limits = &CONFIG.uploads;
         let mut imgs = Vec::new();
+        let mut total = 0;
         while let Some(field) = multipart.next_field().await.or_internal_err()? {
-            let data = field.data.try_concat().await.or_internal_err()?;
+            if imgs.len() == limits.max_files {
+                return Err(Error::payload_too_large());
+            }
+
+            let limit = limits.max_file_size.min(limits.max_body_size - total);
+            let (upload, size) = Upload::spool(field.data, limit).await?;
+            total += size;
+            let name = field.headers.name;
             imgs.push(ImageRequest {
-                filename: field.headers.name,
-                data: ImageData::Bytes(data.to_vec()),
+                filename: Some(name).filter(|n| !n.is_empty()),
+                data: ImageData::Upload(upload),
+                id: query.id,
+                on_conflict: query.on_conflict,
+                thumbnail: service::ThumbnailOptions::default(),
+                presets: Vec::new(),
             })
         }
 
@@ -86,14 +213,162 @@ impl StoreImgRequestBody {
     }
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+/// File of a multipart form spooled to the upload folder, removed when dropped.
+pub(crate) struct Upload(PathBuf);
+
+impl Upload {
+    /// Writes the data of a form field to a new file, failing once it exceeds `limit` bytes.
+    async fn spool<S, C, E>(data: S, limit: u64) -> Result<(Self, u64), Error>
+    where
+        S: Stream<Item = Result<C, E>>,
+        C: AsRef<[u8]>,
+        E: std::fmt::Display,
+    {
+        let folder = CONFIG.upload_folder();
+        tokio::fs::create_dir_all(&folder).await.or_internal_err()?;
+        let seq = UPLOAD_SEQ.fetch_add(1, Ordering::Relaxed);
+        let upload = Upload(folder.join(format!("upload-{}-{}", std::process::id(), seq)));
+
+        let file = File::create(&upload.0).await.or_internal_err()?;
+        let (mut file, size) = data
+            .map_err(|e| Error::internal(format!("{}", e)))
+            .try_fold((file, 0), |(mut file, size), chunk| async move {
+                let chunk = chunk.as_ref();
+                let size = size + chunk.len() as u64;
+                if size > limit {
+                    return Err(Error::payload_too_large());
+                }
+
+                file.write_all(chunk).await.or_internal_err()?;
+                Ok((file, size))
+            })
+            .await?;
+        file.flush().await.or_internal_err()?;
+
+        Ok((upload, size))
+    }
+
+    async fn read(&self) -> Result<Vec<u8>, Error> {
+        tokio::fs::read(&self.0).await.or_internal_err()
+    }
+}
+
+impl Drop for Upload {
+    /// Removes the file in the background, so the executor isn't blocked on the file system.
+    fn drop(&mut self) {
+        let path = std::mem::replace(&mut self.0, PathBuf::new());
+        tokio::spawn(async move {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                warn!("remove upload {}: {}", path.display(), err);
+            }
+        });
+    }
+}
+
+/// How a batch of images is stored when some of them fail.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BatchMode {
+    /// Store all images or none: images not started yet are skipped after the first failure and
+    /// the stored ones are deleted again.
+    Atomic,
+    /// Store every image that can be stored.
+    BestEffort,
+}
+
+impl Default for BatchMode {
+    fn default() -> Self {
+        BatchMode::Atomic
+    }
+}
+
+/// Query parameters of `POST /images`. The naming options apply to every file of multipart
+/// forms, JSON bodies carry them per image.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StoreQuery {
+    #[serde(default)]
+    id: IdScheme,
+    #[serde(default)]
+    on_conflict: service::ConflictPolicy,
+    #[serde(default)]
+    mode: BatchMode,
+}
+
+impl StoreQuery {
+    fn from_request(req: &Request<Body>) -> Result<Self, Error> {
+        match req.uri().query() {
+            Some(q) if !q.is_empty() => {
+                serde_urlencoded::from_str(q).or_bad_request("invalid query")
+            }
+            _ => Ok(Self::default()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StoreStatus {
+    Created,
+    /// Stored, then undone because another image of an atomic batch failed. An image it replaced
+    /// is back.
+    RolledBack,
+    /// Not processed because another image of an atomic batch failed before it was started.
+    Skipped,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub(crate) struct ImageResponse {
-    filename: String,
+    pub(crate) status: StoreStatus,
+    /// Name the image was stored under, or the requested one if it wasn't stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) filename: Option<String>,
+    /// Whether the data of an earlier upload with the same content and options was reused.
+    #[serde(default)]
+    pub(crate) deduplicated: bool,
+    /// Format of the uploaded data, detected from its magic bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) input_format: Option<libvips::InputFormat>,
+    /// Status code of the error, as it would be answered for a single image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) code: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<String>,
 }
 
 impl ImageResponse {
-    pub(crate) fn new(filename: String) -> Self {
-        ImageResponse { filename: filename }
+    pub(crate) fn new(stored: &service::Stored) -> Self {
+        ImageResponse {
+            status: StoreStatus::Created,
+            filename: Some(stored.key.to_string()),
+            deduplicated: stored.deduplicated,
+            input_format: Some(stored.input_format),
+            code: None,
+            reason: None,
+        }
+    }
+
+    fn failed(filename: Option<String>, err: Error) -> Self {
+        err.log();
+        let err = ErrorResponseBody::from_error(err);
+        ImageResponse {
+            status: StoreStatus::Error,
+            code: Some(err.code),
+            reason: Some(err.reason),
+            ..ImageResponse::skipped(filename)
+        }
+    }
+
+    fn skipped(filename: Option<String>) -> Self {
+        ImageResponse {
+            status: StoreStatus::Skipped,
+            filename: filename,
+            deduplicated: false,
+            input_format: None,
+            code: None,
+            reason: None,
+        }
     }
 }
 
@@ -101,49 +376,738 @@ impl ImageResponse {
 pub(crate) struct StoreImgResponseBody(pub(crate) Vec<ImageResponse>);
 
 impl StoreImgResponseBody {
-    fn into_response(self) -> Result<Response<Body>, Error> {
+    /// Answers `201 Created` if every image was stored. A failed atomic batch is answered with
+    /// the status of the failure, a best-effort batch with failures with `207 Multi-Status`.
+    fn into_response(self, mode: BatchMode) -> Result<Response<Body>, Error> {
+        let status = match (self.0.iter().find_map(|img| img.code), mode) {
+            (None, _) => StatusCode::CREATED,
+            (Some(code), BatchMode::Atomic) => StatusCode::from_u16(code).or_internal_err()?,
+            (Some(_), BatchMode::BestEffort) => StatusCode::MULTI_STATUS,
+        };
+
         let json = serde_json::to_string(&self).or_internal_err()?;
         Response::builder()
-            .status(StatusCode::CREATED)
+            .status(status)
             .body(Body::from(json))
             .or_internal_err()
     }
 }
 
 async fn store_img(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let (query, req_body) = parse_store_request(req).await?;
+    let res = store_batch(req_body.0, query.mode, |_, _| {}).await;
+    Ok(StoreImgResponseBody(res)
+        .into_response(query.mode)
+        .context("build response")?)
+}
+
+/// Reads the images to store from a `POST /images` request along with its query.
+async fn parse_store_request(
+    req: Request<Body>,
+) -> Result<(StoreQuery, StoreImgRequestBody), Error> {
+    let query = StoreQuery::from_request(&req)?;
     let headers = req.headers().clone();
     let req_body = match get_content_type(&headers).split(";").next() {
         Some("application/json") => StoreImgRequestBody::from_json_request(req).await,
-        Some("multipart/form-data") => StoreImgRequestBody::from_multipart_request(req).await,
+        Some("multipart/form-data") => {
+            StoreImgRequestBody::from_multipart_request(req, &query).await
+        }
         _ => Err(Error::unsupported_media_type()),
     }
     .context("parse request body")?;
 
-    let path = Path::new(IMG_FOLDER.as_str());
+    Ok((query, req_body))
+}
+
+/// Stores a batch of images, `batch_concurrency` of them at once, with a result per image in the
+/// same order. `progress` is told the index and result of every image once it's processed.
+async fn store_batch<F>(
+    img_reqs: Vec<ImageRequest>,
+    mode: BatchMode,
+    progress: F,
+) -> Vec<ImageResponse>
+where
+    F: Fn(usize, &ImageResponse) + Sync,
+{
+    let storage = STORAGE.as_ref();
+    let atomic = mode == BatchMode::Atomic;
+    let failed = &AtomicBool::new(false);
+    let progress = &progress;
+    let stored: Vec<_> = stream::iter(img_reqs.into_iter().enumerate())
+        .map(|(i, img_req)| async move {
+            let filename = img_req.filename.clone();
+            // Images in flight when an atomic batch fails are finished and rolled back below.
+            if atomic && failed.load(Ordering::SeqCst) {
+                return (None, ImageResponse::skipped(filename));
+            }
+
+            let res = match store_one(img_req, storage).await {
+                Ok(stored) => {
+                    let img = ImageResponse::new(&stored);
+                    (Some(stored), img)
+                }
+                Err(err) => {
+                    failed.store(true, Ordering::SeqCst);
+                    let err = err.context("store img");
+                    (None, ImageResponse::failed(filename, err))
+                }
+            };
+            progress(i, &res.1);
+            res
+        })
+        .buffered(CONFIG.processing.batch_concurrency)
+        .collect()
+        .await;
+
+    // Images of a failed atomic batch are rolled back in reverse order, so an image stored twice
+    // ends up as it was before the batch. Events are only sent for what's committed.
+    let (stored, mut res): (Vec<_>, Vec<_>) = stored.into_iter().unzip();
+    if atomic && failed.load(Ordering::SeqCst) {
+        for (stored, img) in stored.into_iter().zip(res.iter_mut()).rev() {
+            if let Some(stored) = stored {
+                let key = stored.key.clone();
+                match stored.roll_back(storage).await {
+                    Ok(()) => img.status = StoreStatus::RolledBack,
+                    Err(err) => Error::from(err)
+                        .context(&format!("roll back {}", key))
+                        .log(),
+                }
+            }
+        }
+    } else {
+        for stored in stored.into_iter().flatten() {
+            let kind = if stored.replaced {
+                EventKind::Replaced
+            } else {
+                EventKind::Created
+            };
+            let key = stored.key.clone();
+            if let Err(err) = stored.commit(storage).await {
+                Error::from(err).context(&format!("commit {}", key)).log();
+            }
+            webhook::notify(Event::new(kind, &key));
+        }
+    }
+
+    res
+}
+
+/// Stores an image of a batch with the presets it asks for. Nothing is left behind if it fails.
+async fn store_one(img_req: ImageRequest, storage: &dyn Storage) -> Result<Pending, Error> {
+    img_req.check()?;
+    let opts = img_req.thumbnail.clone();
+    let presets = img_req.presets.clone();
+    let policy = img_req.on_conflict;
+    let img = img_req.into_image().await.context("load image")?;
+    let stored = img
+        .store(storage, &opts, policy)
+        .await
+        .context("store img")?;
+    let stored = Pending(Some(stored));
+    for p in presets {
+        let opts = CONFIG.preset(&p).expect("checked preset");
+        if let Err(err) = service::Image::cache_derivative(&stored.key, storage, opts).await {
+            stored.roll_back(storage).await.context("roll back img")?;
+            return Err(Error::from(err).context("render preset"));
+        }
+    }
+
+    Ok(stored)
+}
+
+/// A store neither committed nor rolled back yet. Dropped before, e.g. when the client goes away
+/// during the batch, it's rolled back in the background, so the backup of the image it replaced
+/// doesn't stay behind.
+struct Pending(Option<service::Stored>);
+
+impl Pending {
+    async fn commit(mut self, storage: &dyn Storage) -> Result<(), service::Error> {
+        let stored = self.0.take().expect("pending store");
+        stored.commit(storage).await
+    }
+
+    async fn roll_back(mut self, storage: &dyn Storage) -> Result<(), service::Error> {
+        let stored = self.0.take().expect("pending store");
+        stored.roll_back(storage).await
+    }
+}
+
+impl std::ops::Deref for Pending {
+    type Target = service::Stored;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("pending store")
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(stored) = self.0.take() {
+            tokio::spawn(async move {
+                let key = stored.key.clone();
+                if let Err(err) = stored.roll_back(STORAGE.as_ref()).await {
+                    Error::from(err)
+                        .context(&format!("roll back dropped {}", key))
+                        .log();
+                }
+            });
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeleteStatus {
+    Deleted,
+    NotFound,
+    Error,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct DeleteResult {
+    pub(crate) filename: String,
+    pub(crate) status: DeleteStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<String>,
+}
+
+impl DeleteResult {
+    fn new(filename: String, res: Result<(), Error>) -> Self {
+        let (status, reason) = match res {
+            Ok(()) => (DeleteStatus::Deleted, None),
+            Err(ref err) if err.code == StatusCode::NOT_FOUND => (DeleteStatus::NotFound, None),
+            Err(err) => {
+                err.log();
+                let reason = ErrorResponseBody::from_error(err).reason;
+                (DeleteStatus::Error, Some(reason))
+            }
+        };
+
+        DeleteResult {
+            filename: filename,
+            status: status,
+            reason: reason,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct BatchDeleteRequestBody(pub(crate) Vec<String>);
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct BatchDeleteResponseBody(pub(crate) Vec<DeleteResult>);
+
+async fn delete_img(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let captures = GET_IMG.captures(req.uri().path()).expect("matched route");
+    let key = path_key(&captures[1])?;
+    service::Image::delete(&key, STORAGE.as_ref())
+        .await
+        .context("delete img")?;
+    webhook::notify(Event::new(EventKind::Deleted, &key));
+
+    let res = DeleteResult::new(key.to_string(), Ok(()));
+    let json = serde_json::to_string(&res).or_internal_err()?;
+    Response::builder()
+        .body(Body::from(json))
+        .or_internal_err()
+        .context("build response")
+}
+
+async fn batch_delete_img(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let body = read_body(req).await?;
+    let req_body: BatchDeleteRequestBody =
+        serde_json::from_slice(&body).or_bad_request("invalid json")?;
+
+    let storage = STORAGE.as_ref();
     let mut res = Vec::new();
-    for img_req in req_body.0 {
-        let img = img_req.into_image().await.context("load image")?;
-        let thumb = img.into_thumbnail().await.context("thumbnail img")?;
-        thumb.save(path).await.context("save thumbnail")?;
-        res.push(ImageResponse::new(thumb.filename));
+    for name in req_body.0 {
+        let deleted = match service::image_key(&name) {
+            Ok(key) => {
+                let deleted = service::Image::delete(&key, storage).await;
+                if deleted.is_ok() {
+                    webhook::notify(Event::new(EventKind::Deleted, &key));
+                }
+                deleted.context("delete img")
+            }
+            Err(err) => Err(Error::from(err)),
+        };
+        res.push(DeleteResult::new(name, deleted));
     }
 
-    Ok(StoreImgResponseBody(res)
-        .into_response()
-        .context("build response")?)
+    let json = serde_json::to_string(&BatchDeleteResponseBody(res)).or_internal_err()?;
+    Response::builder()
+        .body(Body::from(json))
+        .or_internal_err()
+        .context("build response")
+}
+
+/// Query parameters of `GET /images`, unknown ones are ignored like for image reads.
+#[derive(Deserialize)]
+struct ListQuery {
+    prefix: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct ListEntry {
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    pub(crate) format: Option<libvips::Format>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    /// RFC 3339 timestamp.
+    pub(crate) created_at: String,
+}
+
+impl ListEntry {
+    fn new(listed: service::Listed) -> Self {
+        let info = listed.info.as_ref();
+        let created_at = info.map(|i| i.created_at).unwrap_or(listed.modified);
+        ListEntry {
+            name: listed.key.to_string(),
+            size: listed.size,
+            format: info.and_then(|i| i.format),
+            width: info.map(|i| i.width),
+            height: info.map(|i| i.height),
+            created_at: humantime::format_rfc3339_seconds(created_at).to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct ListResponseBody {
+    pub(crate) images: Vec<ListEntry>,
+    /// Cursor of the next page, missing on the last one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) next_cursor: Option<String>,
+}
+
+async fn list_img(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let query: ListQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or_default())
+        .or_bad_request("invalid query")?;
+
+    let prefix = ImageKey::parse_name_prefix(query.prefix.as_ref().map_or("", String::as_str))
+        .map_err(|e| Error::bad_request(format!("prefix: {}", e)))?;
+    let cursor = match &query.cursor {
+        Some(c) => Some(
+            ImageKey::parse_name(c).map_err(|e| Error::bad_request(format!("cursor: {}", e)))?,
+        ),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIST_LIMIT);
+    if limit == 0 || limit > MAX_LIST_LIMIT {
+        let details = format!("limit: must be between 1 and {}", MAX_LIST_LIMIT);
+        return Err(Error::bad_request(details));
+    }
+
+    let (listed, next) = service::Image::list(STORAGE.as_ref(), &prefix, cursor, limit)
+        .await
+        .context("list images")?;
+    let body = ListResponseBody {
+        images: listed.into_iter().map(ListEntry::new).collect(),
+        next_cursor: next.map(|key| key.to_string()),
+    };
+
+    let json = serde_json::to_string(&body).or_internal_err()?;
+    Response::builder()
+        .body(Body::from(json))
+        .or_internal_err()
+        .context("build response")
+}
+
+/// How a resized image is fitted into the requested box.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Fit {
+    /// Fit inside the box, preserving the aspect ratio.
+    Contain,
+    /// Cover the whole box, cropping what doesn't fit.
+    Cover,
+    /// Stretch to exactly the box, ignoring the aspect ratio.
+    Fill,
+    /// Like `Contain`, but never enlarge.
+    Inside,
+}
+
+/// Query parameters of image reads. Unknown ones, like cache busters, are ignored.
+#[derive(Deserialize)]
+struct ResizeQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<Fit>,
+    fmt: Option<libvips::Format>,
+    q: Option<u8>,
+}
+
+impl ResizeQuery {
+    /// Missing if none of the parameters is given, the thumbnail is served as it is then.
+    fn from_request(req: &Request<Body>) -> Result<Option<Self>, Error> {
+        let query: Self = match req.uri().query() {
+            Some(q) if !q.is_empty() => {
+                serde_urlencoded::from_str(q).or_bad_request("invalid query")?
+            }
+            _ => return Ok(None),
+        };
+
+        if query.w.is_none()
+            && query.h.is_none()
+            && query.fit.is_none()
+            && query.fmt.is_none()
+            && query.q.is_none()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(query))
+    }
+
+    fn into_options(self) -> service::ThumbnailOptions {
+        let (crop, size) = match self.fit.unwrap_or(Fit::Contain) {
+            Fit::Contain => (libvips::Crop::None, libvips::Size::Both),
+            Fit::Cover => (libvips::Crop::Centre, libvips::Size::Both),
+            Fit::Fill => (libvips::Crop::None, libvips::Size::Force),
+            Fit::Inside => (libvips::Crop::None, libvips::Size::Down),
+        };
+
+        service::ThumbnailOptions {
+            width: self.w,
+            height: self.h,
+            crop: crop,
+            size: size,
+            format: self.fmt.unwrap_or_default(),
+            quality: self.q,
+            ..Default::default()
+        }
+    }
 }
 
 async fn get_img(req: Request<Body>) -> Result<Response<Body>, Error> {
-    let folder = Path::new(IMG_FOLDER.as_str());
-    let filename = req.uri().path().trim_start_matches("/images/").to_string();
-    let img = service::Image::from_storage(filename, folder).await?;
+    let captures = GET_IMG.captures(req.uri().path()).expect("matched route");
+    let key = path_key(&captures[1])?;
+    let opts = ResizeQuery::from_request(&req)?.map(ResizeQuery::into_options);
+    let variant = match &opts {
+        Some(opts) => {
+            opts.check_ad_hoc()?;
+            service::Variant::Derivative(opts)
+        }
+        None => service::Variant::Thumbnail,
+    };
+    serve_img(&req, &key, variant).await
+}
+
+async fn get_original(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let captures = GET_ORIGINAL
+        .captures(req.uri().path())
+        .expect("matched route");
+    let key = path_key(&captures[1])?;
+    serve_img(&req, &key, service::Variant::Original).await
+}
+
+async fn get_preset(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let captures = GET_PRESET
+        .captures(req.uri().path())
+        .expect("matched route");
+    let key = path_key(&captures[1])?;
+    let opts = CONFIG
+        .preset(&captures[2])
+        .ok_or_else(|| Error::not_found("unknown preset".to_string()))?;
+
+    serve_img(&req, &key, service::Variant::Derivative(opts)).await
+}
+
+/// Responds with a variant of the image, or with 304 Not Modified if the client has it already.
+/// HEAD requests are answered without reading the image where possible.
+async fn serve_img(
+    req: &Request<Body>,
+    key: &ImageKey,
+    variant: service::Variant<'_>,
+) -> Result<Response<Body>, Error> {
+    let storage = STORAGE.as_ref();
+    let head = service::Image::head(key, storage, variant).await?;
+    let mut resp = Response::builder();
+    resp.header(ETAG, etag(&head.hash))
+        .header(LAST_MODIFIED, httpdate::fmt_http_date(head.modified))
+        .header(ACCEPT_RANGES, "bytes");
+    if let Some(cache_control) = CONFIG.cache_control() {
+        resp.header(CACHE_CONTROL, cache_control);
+    }
+
+    if is_not_modified(req.headers(), &head) {
+        return resp
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .or_internal_err()
+            .context("build response");
+    }
+
+    let content_type = head.content_type;
+    let body = if *req.method() == Method::HEAD {
+        resp.header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, head.size);
+        Body::empty()
+    } else {
+        let ranges = match req.headers().get(RANGE) {
+            Some(range) if if_range_holds(req.headers(), &head) => {
+                range::parse(range.to_str().unwrap_or_default(), head.size)
+            }
+            _ => Ranges::Full,
+        };
+        ranged_body(&mut resp, ranges, content_type, head.path, head.size)
+    };
+
+    resp.body(body).or_internal_err().context("build response")
+}
+
+/// Makes the body of the requested ranges of the stored image, setting the status and the
+/// headers describing the content.
+fn ranged_body(
+    resp: &mut hyper::http::response::Builder,
+    ranges: Ranges,
+    content_type: &str,
+    path: ImageKey,
+    size: u64,
+) -> Body {
+    let parts = match ranges {
+        Ranges::Full => {
+            resp.header(CONTENT_TYPE, content_type);
+            vec![BodyPart::Range(0..size)]
+        }
+        Ranges::Unsatisfiable => {
+            resp.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", size));
+            return Body::empty();
+        }
+        Ranges::Partial(mut ranges) if ranges.len() == 1 => {
+            let range = ranges.remove(0);
+            resp.status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_TYPE, content_type)
+                .header(CONTENT_RANGE, range::content_range(&range, size));
+            vec![BodyPart::Range(range)]
+        }
+        Ranges::Partial(ranges) => {
+            let boundary = format!("{:016x}", rand::random::<u64>());
+            let mut parts = Vec::with_capacity(3 * ranges.len() + 1);
+            for r in ranges {
+                let part_headers = format!(
+                    "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range::content_range(&r, size),
+                );
+                parts.push(BodyPart::Bytes(part_headers.into_bytes()));
+                parts.push(BodyPart::Range(r));
+                parts.push(BodyPart::Bytes(b"\r\n".to_vec()));
+            }
+            parts.push(BodyPart::Bytes(
+                format!("--{}--\r\n", boundary).into_bytes(),
+            ));
+
+            resp.status(StatusCode::PARTIAL_CONTENT).header(
+                CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={}", boundary),
+            );
+            parts
+        }
+    };
+
+    let len: u64 = parts.iter().map(BodyPart::len).sum();
+    resp.header(CONTENT_LENGTH, len);
+    stream_body(path, parts)
+}
+
+/// Piece of a response body.
+enum BodyPart {
+    Bytes(Vec<u8>),
+    /// Bytes of the stored object.
+    Range(std::ops::Range<u64>),
+}
+
+impl BodyPart {
+    fn len(&self) -> u64 {
+        match self {
+            BodyPart::Bytes(data) => data.len() as u64,
+            BodyPart::Range(range) => range.end - range.start,
+        }
+    }
+}
+
+/// Makes a body sending the parts as the client reads them, so a response holds a chunk of the
+/// object in memory at a time. The connection is closed if the object can't be read in full,
+/// since the announced length can't be met then.
+fn stream_body(path: ImageKey, parts: Vec<BodyPart>) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Err(err) = send_parts(&mut sender, &path, parts).await {
+            warn!("send {}: {}", path, err);
+            sender.abort();
+        }
+    });
+
+    body
+}
+
+async fn send_parts(
+    sender: &mut hyper::body::Sender,
+    path: &ImageKey,
+    parts: Vec<BodyPart>,
+) -> Result<(), String> {
+    for part in parts {
+        let range = match part {
+            BodyPart::Bytes(data) => {
+                sender
+                    .send_data(data.into())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                continue;
+            }
+            BodyPart::Range(range) => range,
+        };
+
+        let len = range.end - range.start;
+        let mut chunks = STORAGE.read(path, range).await.map_err(|e| e.to_string())?;
+        let mut sent = 0;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            sent += chunk.len() as u64;
+            sender
+                .send_data(chunk.into())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        if sent != len {
+            return Err("image changed while being sent".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates `If-Range`: ranges are only sent if the image didn't change since the client got
+/// the part it has. Entity tags are compared strongly, dates must match exactly.
+fn if_range_holds(headers: &HeaderMap<HeaderValue>, head: &service::Head) -> bool {
+    let value = match headers.get(IF_RANGE).map(|v| v.to_str()) {
+        None => return true,
+        Some(Ok(value)) => value.trim(),
+        Some(Err(_)) => return false,
+    };
+
+    if value.starts_with('"') || value.starts_with("W/") {
+        return value == etag(&head.hash);
+    }
+
+    httpdate::parse_http_date(value)
+        .map(|date| httpdate::fmt_http_date(date) == httpdate::fmt_http_date(head.modified))
+        .unwrap_or(false)
+}
+
+/// Evaluates `If-None-Match` or, in its absence, `If-Modified-Since` as described in RFC 7232.
+fn is_not_modified(headers: &HeaderMap<HeaderValue>, head: &service::Head) -> bool {
+    if headers.contains_key(IF_NONE_MATCH) {
+        let etag = etag(&head.hash);
+        return headers
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    let since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    // HTTP dates have a precision of a second.
+    let secs = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    };
+    since.map_or(false, |since| secs(head.modified) <= secs(since))
+}
+
+/// Strong entity tag of an image with the content hash.
+fn etag(hash: &str) -> String {
+    format!("\"{}\"", hash)
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct MetaResponseBody {
+    pub(crate) filename: String,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) bands: u32,
+    pub(crate) format: Option<libvips::Format>,
+    pub(crate) color_space: String,
+    pub(crate) size: u64,
+    pub(crate) has_alpha: bool,
+    /// Options the image was made with, missing if they weren't recorded.
+    pub(crate) options: Option<service::ThumbnailOptions>,
+}
+
+async fn get_meta(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let captures = GET_META.captures(req.uri().path()).expect("matched route");
+    let key = path_key(&captures[1])?;
+    let meta = service::Image::meta(&key, STORAGE.as_ref()).await?;
+    let body = MetaResponseBody {
+        filename: key.to_string(),
+        width: meta.header.width,
+        height: meta.header.height,
+        bands: meta.header.bands,
+        format: meta.format,
+        color_space: meta.header.color_space,
+        size: meta.size,
+        has_alpha: meta.header.has_alpha,
+        options: meta.options,
+    };
+
+    let json = serde_json::to_string(&body).or_internal_err()?;
     Response::builder()
-        .header("Content-Type", "image/jpeg")
-        .body(Body::from(img.data))
+        .body(Body::from(json))
         .or_internal_err()
         .context("build response")
 }
 
+/// Percent-decodes a path segment and parses it as an image name.
+fn path_key(segment: &str) -> Result<ImageKey, Error> {
+    let name = percent_decode_str(segment)
+        .decode_utf8()
+        .map_err(|_| Error::bad_request("filename: must be valid utf-8".to_string()))?;
+    Ok(service::image_key(&name)?)
+}
+
+/// Collects the request body, failing with 413 Payload Too Large once it exceeds the limit.
+async fn read_body(req: Request<Body>) -> Result<Vec<u8>, Error> {
+    check_content_length(&req)?;
+    let limit = CONFIG.uploads.max_body_size;
+    req.into_body()
+        .map_err(|e| Error::internal(format!("{}", e)))
+        .try_fold(Vec::new(), |mut body, chunk| async move {
+            if (body.len() + chunk.len()) as u64 > limit {
+                return Err(Error::payload_too_large());
+            }
+
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        })
+        .await
+}
+
+/// Rejects requests announcing a body over the limit before reading it.
+fn check_content_length(req: &Request<Body>) -> Result<(), Error> {
+    let len = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match len {
+        Some(len) if len > CONFIG.uploads.max_body_size => Err(Error::payload_too_large()),
+        _ => Ok(()),
+    }
+}
+
 fn get_content_type(headers: &HeaderMap<HeaderValue>) -> &str {
     headers
         .get(CONTENT_TYPE)
@@ -161,10 +1125,10 @@ fn log_response(uri: hyper::http::Uri, resp: &Response<Body>) {
     }
 }
 
-#[derive(Serialize)]
-struct ErrorResponseBody {
-    code: u16,
-    reason: String,
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct ErrorResponseBody {
+    pub(crate) code: u16,
+    pub(crate) reason: String,
 }
 
 impl ErrorResponseBody {
@@ -181,10 +1145,13 @@ impl ErrorResponseBody {
 
     fn into_response(self) -> Response<Body> {
         let json = serde_json::to_string(&self).expect("serialize json");
-        Response::builder()
-            .status(self.code)
-            .body(Body::from(json))
-            .expect("build response")
+        let mut resp = Response::builder();
+        resp.status(self.code);
+        if self.code == StatusCode::UNAUTHORIZED.as_u16() {
+            resp.header(WWW_AUTHENTICATE, "Bearer");
+        }
+
+        resp.body(Body::from(json)).expect("build response")
     }
 }
 
@@ -222,6 +1189,15 @@ impl Error {
         Self::new(StatusCode::NOT_FOUND, cause)
     }
 
+    fn payload_too_large() -> Self {
+        let limits = &CONFIG.uploads;
+        let cause = format!(
+            "requests must be at most {} bytes, with at most {} files of {} bytes",
+            limits.max_body_size, limits.max_files, limits.max_file_size,
+        );
+        Self::new(StatusCode::PAYLOAD_TOO_LARGE, cause)
+    }
+
     fn unsupported_media_type() -> Self {
         let code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
         Self::new(code, Error::default_cause(code))
@@ -251,11 +1227,27 @@ impl From<service::Error> for Error {
             }
             service::ErrorKind::Internal => Self::internal(format!("{}", err.cause)),
             service::ErrorKind::NotFound => Self::not_found(String::default()),
+            service::ErrorKind::Conflict => {
+                Self::new(StatusCode::CONFLICT, format!("{}", err.cause))
+            }
+            service::ErrorKind::UnsupportedFormat => {
+                Self::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("{}", err.cause))
+            }
         }
         .context(&err.backtrace)
     }
 }
 
+impl From<auth::Error> for Error {
+    fn from(err: auth::Error) -> Self {
+        let code = match err {
+            auth::Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
 trait WrapError<T> {
     fn or_internal_err(self) -> Result<T, Error>;
     fn or_bad_request(self, details: &str) -> Result<T, Error>;
@@ -0,0 +1,220 @@
+use super::super::config::CONFIG;
+use super::super::key::{self, ImageKey};
+use super::super::limit::{self, Limiter};
+use super::{
+    parse_store_request, store_batch, BatchMode, Error, ErrorContext, ImageRequest, ImageResponse,
+    WrapError, STORAGE,
+};
+use hyper::http::header::LOCATION;
+use hyper::{Body, Request, Response, StatusCode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Folder (relative to the storage root) holding the state of jobs.
+const JOBS_FOLDER: &str = ".jobs";
+
+/// Saved jobs listed at once by `expire`.
+const EXPIRE_PAGE_SIZE: usize = 1000;
+
+/// Set once the server shuts down, new jobs are refused from then on.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    pub(super) static ref GET_JOB: Regex = Regex::new(r"^/jobs/([0-9A-Z]{26})$").expect("regexp");
+
+    /// Jobs queued or running in this process.
+    static ref JOBS: Mutex<HashMap<String, Arc<Mutex<Job>>>> = Mutex::new(HashMap::new());
+
+    static ref WORKERS: Limiter = Limiter::new(CONFIG.processing.job_workers);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    /// The server stopped before the job was done without draining it, e.g. it crashed.
+    Interrupted,
+}
+
+/// Batch of images stored in the background. Its state is saved under `.jobs` when it's queued,
+/// started and done, the progress in between is only kept in memory.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Job {
+    pub(crate) id: String,
+    pub(crate) status: JobStatus,
+    pub(crate) mode: BatchMode,
+    pub(crate) created_at: String,
+    /// Images processed so far.
+    pub(crate) processed: usize,
+    /// A result per image in the order of the request, `null` until the image is processed.
+    pub(crate) results: Vec<Option<ImageResponse>>,
+}
+
+impl Job {
+    fn key(id: &str) -> ImageKey {
+        ImageKey::parse(&format!("{}/{}.json", JOBS_FOLDER, id)).expect("valid job key")
+    }
+
+    /// Looks the job up among the ones of this process first, then in the storage.
+    async fn load(id: &str) -> Result<Self, Error> {
+        let running = JOBS.lock().expect("lock jobs").get(id).cloned();
+        if let Some(job) = running {
+            return Ok(job.lock().expect("lock job").clone());
+        }
+
+        let json = match STORAGE.get(&Job::key(id)).await {
+            Err(ref err) if err.is_not_found() => {
+                return Err(Error::not_found("no such job".to_string()))
+            }
+            res => res.map_err(|e| Error::internal(e.to_string()))?,
+        };
+
+        let mut job: Self = serde_json::from_slice(&json).or_internal_err()?;
+        if job.status != JobStatus::Done {
+            job.status = JobStatus::Interrupted;
+        }
+        Ok(job)
+    }
+
+    async fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_vec(self).or_internal_err()?;
+        STORAGE
+            .put(&Job::key(&self.id), json)
+            .await
+            .map_err(|e| Error::internal(e.to_string()))
+    }
+
+    fn to_response(&self, status: StatusCode) -> Result<Response<Body>, Error> {
+        let json = serde_json::to_string(self).or_internal_err()?;
+        Response::builder()
+            .status(status)
+            .header(LOCATION, format!("/jobs/{}", self.id).as_str())
+            .body(Body::from(json))
+            .or_internal_err()
+    }
+}
+
+/// Queues the images of a request like the ones of `POST /images` as a job, answered with
+/// `202 Accepted` right away.
+pub(super) async fn submit(req: Request<Body>) -> Result<Response<Body>, Error> {
+    if DRAINING.load(Ordering::SeqCst) {
+        let cause = "shutting down".to_string();
+        return Err(Error::new(StatusCode::SERVICE_UNAVAILABLE, cause));
+    }
+
+    let (query, req_body) = parse_store_request(req).await?;
+    let job = Job {
+        id: key::ulid(),
+        status: JobStatus::Queued,
+        mode: query.mode,
+        created_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        processed: 0,
+        results: vec![None; req_body.0.len()],
+    };
+    job.save().await.context("save job")?;
+
+    let resp = job.to_response(StatusCode::ACCEPTED)?;
+    let id = job.id.clone();
+    let job = Arc::new(Mutex::new(job));
+    JOBS.lock().expect("lock jobs").insert(id, job.clone());
+    tokio::spawn(run(job, req_body.0));
+    Ok(resp)
+}
+
+pub(super) async fn get(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let captures = GET_JOB.captures(req.uri().path()).expect("matched route");
+    let job = Job::load(&captures[1]).await.context("load job")?;
+    job.to_response(StatusCode::OK)
+}
+
+/// Runs the job once a worker is free, then forgets it in favour of the saved state.
+async fn run(job: Arc<Mutex<Job>>, img_reqs: Vec<ImageRequest>) {
+    let _worker = WORKERS.acquire().await;
+    update(&job, |job| job.status = JobStatus::Running).await;
+    let (id, mode) = {
+        let job = job.lock().expect("lock job");
+        (job.id.clone(), job.mode)
+    };
+
+    let results = store_batch(img_reqs, mode, |i, res| {
+        let mut job = job.lock().expect("lock job");
+        job.results[i] = Some(res.clone());
+        job.processed += 1;
+    })
+    .await;
+
+    update(&job, |job| {
+        job.status = JobStatus::Done;
+        job.processed = results.len();
+        job.results = results.into_iter().map(Some).collect();
+    })
+    .await;
+    JOBS.lock().expect("lock jobs").remove(&id);
+
+    expire(Duration::from_secs(CONFIG.jobs.retention_secs)).await;
+}
+
+/// Deletes the saved state of the jobs last changed more than `retention` ago, except for the
+/// ones of this process, which are still queued or running. Failures are only logged.
+pub(crate) async fn expire(retention: Duration) {
+    if let Err(err) = delete_expired(retention).await {
+        err.context("expire jobs").log();
+    }
+}
+
+async fn delete_expired(retention: Duration) -> Result<(), Error> {
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .unwrap_or(UNIX_EPOCH);
+    let folder = ImageKey::parse(&format!("{}/", JOBS_FOLDER)).expect("valid jobs folder");
+    let mut start_after = None;
+    loop {
+        let objects = STORAGE
+            .list(&folder, start_after.as_ref(), EXPIRE_PAGE_SIZE)
+            .await
+            .map_err(|e| Error::internal(e.to_string()))?;
+        let last = match objects.last() {
+            Some(obj) => obj.key.clone(),
+            None => return Ok(()),
+        };
+
+        for obj in objects {
+            let id = obj.key.as_str()[folder.as_str().len()..].trim_end_matches(".json");
+            if obj.modified > cutoff || JOBS.lock().expect("lock jobs").contains_key(id) {
+                continue;
+            }
+
+            match STORAGE.delete(&obj.key).await {
+                Err(ref err) if err.is_not_found() => {}
+                res => res.map_err(|e| Error::internal(e.to_string()))?,
+            }
+        }
+        start_after = Some(last);
+    }
+}
+
+/// Changes the job and saves it. A failed save is only logged, the job goes on in memory.
+async fn update<F: FnOnce(&mut Job)>(job: &Mutex<Job>, f: F) {
+    let snapshot = {
+        let mut job = job.lock().expect("lock job");
+        f(&mut job);
+        job.clone()
+    };
+
+    if let Err(err) = snapshot.save().await {
+        err.context(&format!("save job {}", snapshot.id)).log();
+    }
+}
+
+/// Refuses new jobs and waits for the queued and running ones to be done, so a graceful
+/// shutdown doesn't lose them.
+pub(crate) async fn drain() {
+    DRAINING.store(true, Ordering::SeqCst);
+    limit::wait_until_done("jobs", || JOBS.lock().expect("lock jobs").len()).await
+}
@@ -0,0 +1,94 @@
+use super::config::AuthConfig;
+use hyper::http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+
+/// Header carrying the API key, as an alternative to `Authorization: Bearer {key}`.
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// What an API key grants.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    Read,
+    Write,
+    Delete,
+    /// Everything the other scopes grant.
+    Admin,
+}
+
+impl Scope {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Delete => "delete",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// Checks the request carries an unexpired API key granting the scope. Everything is allowed if
+/// the service is configured to be open.
+pub(crate) fn authorize(
+    cfg: &AuthConfig,
+    headers: &HeaderMap<HeaderValue>,
+    scope: Scope,
+) -> Result<(), Error> {
+    if cfg.open {
+        return Ok(());
+    }
+
+    // Keys are compared by their digests, so the time taken doesn't tell how much of a guess
+    // was right.
+    let presented = Sha256::digest(presented_key(headers).ok_or(Error::Missing)?.as_bytes());
+    let key = cfg
+        .keys
+        .iter()
+        .find(|k| Sha256::digest(k.key.as_bytes()) == presented)
+        .ok_or(Error::Invalid)?;
+
+    if key.is_expired(SystemTime::now()) {
+        return Err(Error::Expired);
+    }
+
+    if !key.scopes.iter().any(|&s| s == scope || s == Scope::Admin) {
+        return Err(Error::Forbidden(scope));
+    }
+
+    Ok(())
+}
+
+fn presented_key(headers: &HeaderMap<HeaderValue>) -> Option<&str> {
+    if let Some(value) = headers.get(API_KEY_HEADER) {
+        return value.to_str().ok();
+    }
+
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(key)) if scheme.eq_ignore_ascii_case("bearer") => Some(key.trim()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Error {
+    Missing,
+    Invalid,
+    Expired,
+    /// The key is valid, but doesn't grant the scope.
+    Forbidden(Scope),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Missing => write!(f, "missing api key"),
+            Error::Invalid => write!(f, "invalid api key"),
+            Error::Expired => write!(f, "api key expired"),
+            Error::Forbidden(scope) => write!(f, "api key lacks the {} scope", scope.name()),
+        }
+    }
+}
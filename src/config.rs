@@ -0,0 +1,534 @@
+use super::{auth, libvips, service, webhook};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::io::ErrorKind::NotFound as IONotFound;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Names reserved for the routes serving the untouched upload and the image metadata.
+const RESERVED_PRESETS: &[&str] = &["original", "meta"];
+
+/// Config file read when `CONFIG_FILE` isn't set.
+#[cfg(not(test))]
+const DEFAULT_CONFIG_FILE: &str = "config.json";
+
+/// Tests read their own config, with limits small enough to hit.
+#[cfg(test)]
+const DEFAULT_CONFIG_FILE: &str = "config.test.json";
+
+lazy_static! {
+    pub(crate) static ref CONFIG: Config = Config::load();
+}
+
+/// Service configuration, read from the JSON file at `CONFIG_FILE` (`config.json` by default).
+/// A missing file means the defaults.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Named thumbnail options clients may refer to instead of sending raw dimensions.
+    pub(crate) presets: HashMap<String, service::ThumbnailOptions>,
+    pub(crate) resizing: ResizingPolicy,
+    pub(crate) storage: StorageConfig,
+    /// `Cache-Control` header of image responses, not sent if missing. See `cache_control()`.
+    pub(crate) cache_control: Option<String>,
+    pub(crate) uploads: UploadLimits,
+    pub(crate) decode: DecodeLimits,
+    /// Formats accepted for uploads, others are rejected with 415 Unsupported Media Type.
+    pub(crate) input_formats: Vec<libvips::InputFormat>,
+    pub(crate) fetch: FetchPolicy,
+    pub(crate) http_client: HttpClientConfig,
+    pub(crate) processing: ProcessingLimits,
+    pub(crate) jobs: JobConfig,
+    pub(crate) webhooks: WebhookConfig,
+    pub(crate) auth: AuthConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        use libvips::InputFormat::*;
+        Self {
+            presets: HashMap::new(),
+            resizing: ResizingPolicy::default(),
+            storage: StorageConfig::default(),
+            cache_control: None,
+            uploads: UploadLimits::default(),
+            decode: DecodeLimits::default(),
+            input_formats: vec![Jpeg, Png, Webp, Gif, Heif, Avif, Tiff],
+            fetch: FetchPolicy::default(),
+            http_client: HttpClientConfig::default(),
+            processing: ProcessingLimits::default(),
+            jobs: JobConfig::default(),
+            webhooks: WebhookConfig::default(),
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+/// What derivatives clients may ask for with query parameters. Every distinct set of parameters
+/// renders and caches another file, presets aren't limited by it.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ResizingPolicy {
+    /// Whether query parameters are accepted at all.
+    pub(crate) ad_hoc: bool,
+    /// Largest width or height.
+    pub(crate) max_size: u32,
+    /// Widths and heights that may be asked for, any up to `max_size` if empty.
+    pub(crate) sizes: Vec<u32>,
+}
+
+impl Default for ResizingPolicy {
+    fn default() -> Self {
+        Self {
+            ad_hoc: true,
+            max_size: 2048,
+            sizes: Vec::new(),
+        }
+    }
+}
+
+/// Bounds of request bodies and uploads, larger ones are rejected with 413 Payload Too Large.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UploadLimits {
+    /// Bytes of a whole request body.
+    pub(crate) max_body_size: u64,
+    /// Bytes of a single file of a multipart form.
+    pub(crate) max_file_size: u64,
+    /// Images of a single request.
+    pub(crate) max_files: usize,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self {
+            max_body_size: 64 << 20,
+            max_file_size: 16 << 20,
+            max_files: 16,
+        }
+    }
+}
+
+/// Bounds of the images libvips decodes, checked against the header before decoding, so small
+/// files declaring huge images can't exhaust memory.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DecodeLimits {
+    pub(crate) max_width: u32,
+    pub(crate) max_height: u32,
+    /// Width times height.
+    pub(crate) max_pixels: u64,
+    /// Frames of animated images or pages of multi-page documents.
+    pub(crate) max_pages: u32,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 16_384,
+            max_height: 16_384,
+            max_pixels: 100_000_000,
+            max_pages: 100,
+        }
+    }
+}
+
+/// How much image processing is done at once.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ProcessingLimits {
+    /// Images of a single request fetched, decoded and thumbnailed at once.
+    pub(crate) batch_concurrency: usize,
+    /// Images decoded and thumbnailed by libvips at once across the whole server.
+    pub(crate) vips_concurrency: usize,
+    /// Jobs of `POST /jobs` run at once, further ones are queued.
+    pub(crate) job_workers: usize,
+}
+
+impl Default for ProcessingLimits {
+    fn default() -> Self {
+        Self {
+            batch_concurrency: 4,
+            vips_concurrency: 4,
+            job_workers: 2,
+        }
+    }
+}
+
+/// What is kept of the jobs of `POST /jobs`.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct JobConfig {
+    /// Seconds the state of a job is kept after its last change, see `jobs::expire`.
+    pub(crate) retention_secs: u64,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            retention_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// What remote image sources may be fetched. Hosts match themselves and their subdomains.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FetchPolicy {
+    pub(crate) schemes: Vec<String>,
+    /// Any host not denied if empty.
+    pub(crate) allowed_hosts: Vec<String>,
+    pub(crate) denied_hosts: Vec<String>,
+    /// Whether hosts may resolve to loopback, private, link-local and other non-public addresses.
+    pub(crate) allow_private_ips: bool,
+    pub(crate) max_redirects: usize,
+    /// Seconds for the whole download, redirects included.
+    pub(crate) timeout_secs: u64,
+    pub(crate) max_size: u64,
+    /// Accepted `Content-Type`s of responses, `type/*` matches any subtype.
+    pub(crate) content_types: Vec<String>,
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        Self {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            allow_private_ips: false,
+            max_redirects: 5,
+            timeout_secs: 10,
+            max_size: 16 << 20,
+            content_types: vec!["image/*".to_string()],
+        }
+    }
+}
+
+/// Settings of the client shared by all remote fetches.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HttpClientConfig {
+    pub(crate) user_agent: String,
+    /// Proxy for all requests, taken from the environment by default, see `env_proxy`. Hosts are
+    /// resolved by the proxy then, so private addresses are only kept out by
+    /// the checks of the URL.
+    pub(crate) proxy: Option<String>,
+    /// Idle connections kept open per host.
+    pub(crate) max_idle_per_host: usize,
+    /// Requests to the same host at once.
+    pub(crate) max_per_host: usize,
+    /// Retries of requests failing to connect, timing out or answered with a server error. Hosts
+    /// denied by the fetch policy fail right away.
+    pub(crate) retries: u32,
+    /// Milliseconds before the first retry, doubled for every further one.
+    pub(crate) retry_backoff_ms: u64,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string(),
+            proxy: env_proxy(),
+            max_idle_per_host: 8,
+            max_per_host: 4,
+            retries: 2,
+            retry_backoff_ms: 200,
+        }
+    }
+}
+
+/// The first of the usual proxy environment variables that is set, HTTPS first since it's the
+/// only proxy used for all requests.
+fn env_proxy() -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|proxy| !proxy.is_empty())
+}
+
+/// Endpoints notified of image lifecycle events, and how events are delivered to them.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct WebhookConfig {
+    pub(crate) endpoints: Vec<WebhookEndpoint>,
+    /// Retries of deliveries failing to connect or not answered with a success status.
+    pub(crate) retries: u32,
+    /// Milliseconds before the first retry, doubled for every further one.
+    pub(crate) retry_backoff_ms: u64,
+    /// Seconds for a single delivery attempt.
+    pub(crate) timeout_secs: u64,
+    /// JSON Lines file recording the events that never got through, see
+    /// `Config::dead_letter_log`.
+    pub(crate) dead_letter_log: Option<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            retries: 5,
+            retry_backoff_ms: 1000,
+            timeout_secs: 10,
+            dead_letter_log: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebhookEndpoint {
+    pub(crate) url: String,
+    /// Key of the HMAC-SHA256 signature of the events.
+    pub(crate) secret: String,
+    /// Events sent to the endpoint, all of them if empty.
+    #[serde(default)]
+    pub(crate) events: Vec<webhook::EventKind>,
+}
+
+impl WebhookEndpoint {
+    pub(crate) fn subscribes(&self, kind: webhook::EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+/// API keys the endpoints require, see `auth::authorize`. At least one is needed, unless the
+/// service is explicitly open.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+    /// Serves every endpoint without a key, instead of refusing to start without keys.
+    pub(crate) open: bool,
+    pub(crate) keys: Vec<ApiKey>,
+    /// JSON file with a list of further keys, read at startup.
+    pub(crate) key_file: Option<String>,
+    /// Whether images, their originals, presets and metadata may be read without a key.
+    pub(crate) public_reads: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ApiKey {
+    /// Tells keys apart in logs.
+    #[serde(default)]
+    pub(crate) name: String,
+    pub(crate) key: String,
+    pub(crate) scopes: Vec<auth::Scope>,
+    /// RFC 3339 time the key stops working at, it never does if missing.
+    #[serde(default)]
+    pub(crate) expires_at: Option<String>,
+}
+
+impl ApiKey {
+    pub(crate) fn is_expired(&self, now: SystemTime) -> bool {
+        match &self.expires_at {
+            Some(t) => humantime::parse_rfc3339_weak(t).expect("validated expiry") <= now,
+            None => false,
+        }
+    }
+}
+
+/// Where images are kept, selected by the `backend` field.
+#[derive(Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub(crate) enum StorageConfig {
+    /// Files under `folder` (`IMG_FOLDER` or `images` by default).
+    Fs {
+        #[serde(default = "default_folder")]
+        folder: String,
+    },
+    /// Process memory, lost on restart.
+    Memory,
+    /// A bucket of an S3 compatible service.
+    S3(S3Config),
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Fs {
+            folder: default_folder(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct S3Config {
+    pub(crate) endpoint: String,
+    pub(crate) bucket: String,
+    #[serde(default = "default_region")]
+    pub(crate) region: String,
+    /// `AWS_ACCESS_KEY_ID` by default.
+    #[serde(default = "default_access_key")]
+    pub(crate) access_key: String,
+    /// `AWS_SECRET_ACCESS_KEY` by default.
+    #[serde(default = "default_secret_key")]
+    pub(crate) secret_key: String,
+}
+
+fn default_folder() -> String {
+    env::var("IMG_FOLDER").unwrap_or("images".to_string())
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_access_key() -> String {
+    env::var("AWS_ACCESS_KEY_ID").unwrap_or_default()
+}
+
+fn default_secret_key() -> String {
+    env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default()
+}
+
+impl Config {
+    fn load() -> Self {
+        let path = env::var("CONFIG_FILE").unwrap_or(DEFAULT_CONFIG_FILE.to_string());
+        let mut cfg: Self = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).expect("parse config"),
+            Err(ref err) if err.kind() == IONotFound => {
+                info!("{} not found, using default config", path);
+                Self::default()
+            }
+            Err(err) => panic!("read config {}: {}", path, err),
+        };
+
+        if let Some(path) = &cfg.auth.key_file {
+            let data =
+                std::fs::read(path).unwrap_or_else(|err| panic!("read key file {}: {}", path, err));
+            let keys: Vec<ApiKey> = serde_json::from_slice(&data).expect("parse key file");
+            cfg.auth.keys.extend(keys);
+        }
+
+        cfg.validate();
+        cfg
+    }
+
+    fn validate(&self) {
+        let limits = &self.uploads;
+        if limits.max_body_size == 0 || limits.max_file_size == 0 || limits.max_files == 0 {
+            panic!("invalid upload limits: must be positive");
+        }
+
+        if self.fetch.timeout_secs == 0 || self.fetch.max_size == 0 {
+            panic!("invalid fetch policy: timeout and size must be positive");
+        }
+
+        if self.auth.keys.is_empty() && !self.auth.open {
+            panic!("no api keys configured: add auth.keys or auth.key_file, or set auth.open");
+        }
+
+        if self.auth.open && !self.auth.keys.is_empty() {
+            panic!("invalid auth config: open with api keys");
+        }
+
+        for key in &self.auth.keys {
+            if key.key.is_empty() || key.scopes.is_empty() {
+                panic!("invalid api key {:?}: needs a key and scopes", key.name);
+            }
+
+            let expiry = key
+                .expires_at
+                .as_ref()
+                .map(|t| humantime::parse_rfc3339_weak(t));
+            if let Some(Err(err)) = expiry {
+                panic!("invalid api key {:?}: expires_at: {}", key.name, err);
+            }
+        }
+
+        if self.webhooks.timeout_secs == 0 {
+            panic!("invalid webhook config: timeout must be positive");
+        }
+
+        for endpoint in &self.webhooks.endpoints {
+            if reqwest::Url::parse(&endpoint.url).is_err() || endpoint.secret.is_empty() {
+                panic!(
+                    "invalid webhook endpoint {:?}: needs a url and a secret",
+                    endpoint.url
+                );
+            }
+        }
+
+        if self.http_client.max_per_host == 0 {
+            panic!("invalid http client config: max_per_host must be positive");
+        }
+
+        let limits = &self.processing;
+        if limits.batch_concurrency == 0 || limits.vips_concurrency == 0 || limits.job_workers == 0
+        {
+            panic!("invalid processing limits: must be positive");
+        }
+
+        if self.jobs.retention_secs == 0 {
+            panic!("invalid job config: retention_secs must be positive");
+        }
+
+        let limits = &self.decode;
+        if limits.max_width == 0
+            || limits.max_height == 0
+            || limits.max_pixels == 0
+            || limits.max_pages == 0
+        {
+            panic!("invalid decode limits: must be positive");
+        }
+
+        let resizing = &self.resizing;
+        if resizing.max_size == 0
+            || resizing
+                .sizes
+                .iter()
+                .any(|&s| s == 0 || s > resizing.max_size)
+        {
+            panic!("invalid resizing policy: sizes must be between 1 and max_size");
+        }
+
+        for (name, opts) in &self.presets {
+            if name.is_empty() || name.contains('/') || RESERVED_PRESETS.contains(&name.as_str()) {
+                panic!("invalid preset name: {:?}", name);
+            }
+
+            if let Err(err) = opts.validate() {
+                panic!("invalid preset {}: {:?}", name, err.kind);
+            }
+        }
+    }
+
+    /// Folder multipart uploads are spooled to: the one of the file storage, `IMG_FOLDER` for
+    /// other backends.
+    pub(crate) fn upload_folder(&self) -> PathBuf {
+        self.local_folder().join(".tmp")
+    }
+
+    /// File recording the webhook events that never got through: the configured one, or one in
+    /// the folder of the file storage (`IMG_FOLDER` for other backends).
+    pub(crate) fn dead_letter_log(&self) -> PathBuf {
+        match &self.webhooks.dead_letter_log {
+            Some(path) => PathBuf::from(path),
+            None => self.local_folder().join(".webhooks/dead_letters.jsonl"),
+        }
+    }
+
+    fn local_folder(&self) -> PathBuf {
+        let folder = match &self.storage {
+            StorageConfig::Fs { folder } => folder.clone(),
+            _ => default_folder(),
+        };
+
+        PathBuf::from(folder)
+    }
+
+    /// `Cache-Control` header of image responses. Images that need a key to be read are
+    /// `private`, so shared caches don't hand them out to anyone.
+    pub(crate) fn cache_control(&self) -> Option<&str> {
+        if !self.auth.open && !self.auth.public_reads {
+            return Some("private");
+        }
+
+        self.cache_control.as_ref().map(String::as_str)
+    }
+
+    pub(crate) fn preset(&self, name: &str) -> Option<&service::ThumbnailOptions> {
+        self.presets.get(name)
+    }
+}
@@ -0,0 +1,429 @@
+use super::config::{FetchPolicy, HttpClientConfig, CONFIG};
+use super::limit::{self, Limiter};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use hyper::client::connect::dns::{Name, Resolve};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, USER_AGENT};
+use hyper::{Body, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use reqwest::{RedirectPolicy, Url};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::timer::{delay_for, Timeout};
+
+/// Client connecting to remote hosts itself, at the addresses `CheckedResolver` lets through.
+type DirectClient = hyper::Client<HttpsConnector<HttpConnector<CheckedResolver>>>;
+
+lazy_static! {
+    /// Shared by all fetches without a proxy, so connections are pooled. One for policies
+    /// keeping private addresses out, one for those allowing them.
+    static ref PUBLIC_CLIENT: DirectClient = new_direct_client(&CONFIG.http_client, false);
+    static ref PRIVATE_CLIENT: DirectClient = new_direct_client(&CONFIG.http_client, true);
+    /// Shared by all fetches through the configured proxy.
+    static ref PROXY_CLIENT: Option<reqwest::Client> = CONFIG
+        .http_client
+        .proxy
+        .as_ref()
+        .map(|proxy| new_proxy_client(&CONFIG.http_client, proxy));
+    /// Caps the requests to each host, entries are dropped once idle.
+    static ref HOSTS: Mutex<HashMap<String, Limiter>> = Mutex::new(HashMap::new());
+}
+
+/// Builds a client for fetches without a proxy. Redirects are left to `fetch`, which checks
+/// every location.
+fn new_direct_client(cfg: &HttpClientConfig, allow_private_ips: bool) -> DirectClient {
+    let mut http = HttpConnector::new_with_resolver(CheckedResolver {
+        allow_private_ips: allow_private_ips,
+    });
+    http.enforce_http(false);
+    let tls: tokio_tls::TlsConnector = native_tls::TlsConnector::new()
+        .expect("build tls connector")
+        .into();
+
+    hyper::Client::builder()
+        .max_idle_per_host(cfg.max_idle_per_host)
+        .build(HttpsConnector::from((http, tls)))
+}
+
+/// Builds the client for fetches through the proxy. Redirects are left to `fetch`, which checks
+/// every location. The proxy comes from the configuration, which reads the environment by default.
+fn new_proxy_client(cfg: &HttpClientConfig, proxy: &str) -> reqwest::Client {
+    let mut headers = HeaderMap::new();
+    let user_agent = HeaderValue::from_str(&cfg.user_agent).expect("valid user agent");
+    headers.insert(USER_AGENT, user_agent);
+
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .redirect(RedirectPolicy::none())
+        .max_idle_per_host(cfg.max_idle_per_host)
+        .proxy(reqwest::Proxy::all(proxy).expect("valid proxy url"))
+        .build()
+        .expect("build http client")
+}
+
+/// Resolves hosts for direct fetches, failing for hosts with addresses the policy doesn't allow.
+/// Connections go to the addresses checked here, so a host can't pass `check_url` with a public
+/// address and resolve to a private one when connecting.
+#[derive(Clone)]
+pub(crate) struct CheckedResolver {
+    pub(crate) allow_private_ips: bool,
+}
+
+impl Resolve for CheckedResolver {
+    type Addrs = std::vec::IntoIter<IpAddr>;
+    type Future = BoxFuture<'static, Result<Self::Addrs, std::io::Error>>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let allow_private_ips = self.allow_private_ips;
+        async move {
+            let ips: Vec<IpAddr> = tokio_executor::blocking::run(move || {
+                (name.as_str(), 0)
+                    .to_socket_addrs()
+                    .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+            })
+            .await?;
+
+            match ips.iter().find(|&&ip| !allow_private_ips && !is_public(ip)) {
+                Some(&ip) => Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    Error::PrivateAddress(ip).to_string(),
+                )),
+                None => Ok(ips.into_iter()),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Limiter of the requests to the host.
+fn host_limiter(host: &str) -> Limiter {
+    let mut hosts = HOSTS.lock().expect("lock hosts");
+    hosts.retain(|_, limiter| !limiter.is_idle());
+    hosts
+        .entry(host.to_string())
+        .or_insert_with(|| Limiter::new(CONFIG.http_client.max_per_host))
+        .clone()
+}
+
+/// Downloads a remote image under the policy. Redirects are followed one by one, so every
+/// location is checked like the first one. Without a proxy, connections only go to addresses
+/// checked against the policy, see `CheckedResolver`.
+pub(crate) async fn fetch(policy: &FetchPolicy, uri: &str) -> Result<Vec<u8>, Error> {
+    let timeout = Duration::from_secs(policy.timeout_secs);
+    Timeout::new(fetch_following(policy, uri), timeout)
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+async fn fetch_following(policy: &FetchPolicy, uri: &str) -> Result<Vec<u8>, Error> {
+    let mut url = Url::parse(uri).map_err(|e| Error::InvalidUrl(e.to_string()))?;
+    for _ in 0..=policy.max_redirects {
+        let host = check_url(policy, &url).await?;
+        let _permit = host_limiter(&host).acquire().await;
+        let mut resp = send(policy, &url).await?;
+
+        if resp.status.is_redirection() {
+            let location = resp
+                .headers
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(Error::Status(resp.status.as_u16()))?;
+            url = url
+                .join(location)
+                .map_err(|e| Error::InvalidUrl(e.to_string()))?;
+            continue;
+        }
+
+        if !resp.status.is_success() {
+            return Err(Error::Status(resp.status.as_u16()));
+        }
+
+        let content_type = resp
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !content_type_allowed(policy, content_type) {
+            return Err(Error::ContentType(content_type.to_string()));
+        }
+
+        let len = resp
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if len.map_or(false, |len| len > policy.max_size) {
+            return Err(Error::TooLarge(policy.max_size));
+        }
+
+        // The length may be missing or wrong, so it's counted while reading too.
+        let mut data = Vec::new();
+        while let Some(chunk) = resp.body.try_next().await? {
+            if (data.len() + chunk.len()) as u64 > policy.max_size {
+                return Err(Error::TooLarge(policy.max_size));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        return Ok(data);
+    }
+
+    Err(Error::TooManyRedirects(policy.max_redirects))
+}
+
+/// Response of either client, with the body read in chunks.
+struct Response {
+    status: StatusCode,
+    headers: HeaderMap<HeaderValue>,
+    body: BoxStream<'static, Result<Vec<u8>, Error>>,
+}
+
+/// Sends a GET request, retrying with exponential backoff while it fails to connect, times out
+/// or gets a server error. Connections the policy denies fail right away.
+async fn send(policy: &FetchPolicy, url: &Url) -> Result<Response, Error> {
+    let cfg = &CONFIG.http_client;
+    let mut attempt = 0;
+    loop {
+        let res = get(policy, url).await;
+        let retry = match &res {
+            Ok(resp) => resp.status.is_server_error(),
+            Err(err) => err.is_transient(),
+        };
+        if !retry || attempt == cfg.retries {
+            return res;
+        }
+
+        delay_for(limit::backoff(cfg.retry_backoff_ms, attempt)).await;
+        attempt += 1;
+    }
+}
+
+async fn get(policy: &FetchPolicy, url: &Url) -> Result<Response, Error> {
+    if let Some(client) = PROXY_CLIENT.as_ref() {
+        let resp = client.get(url.clone()).send().await?;
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = stream::unfold(Some(resp), |resp| async move {
+            let mut resp = resp?;
+            match resp.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), Some(resp))),
+                Ok(None) => None,
+                Err(err) => Some((Err(Error::from(err)), None)),
+            }
+        });
+
+        return Ok(Response {
+            status: status,
+            headers: headers,
+            body: body.boxed(),
+        });
+    }
+
+    let client = if policy.allow_private_ips {
+        &*PRIVATE_CLIENT
+    } else {
+        &*PUBLIC_CLIENT
+    };
+    let uri: Uri = url
+        .as_str()
+        .parse()
+        .map_err(|e: hyper::http::uri::InvalidUri| Error::InvalidUrl(e.to_string()))?;
+    let req = Request::get(uri)
+        .header(USER_AGENT, CONFIG.http_client.user_agent.as_str())
+        .body(Body::empty())
+        .map_err(|e| Error::InvalidUrl(e.to_string()))?;
+
+    let (parts, body) = client.request(req).await?.into_parts();
+    Ok(Response {
+        status: parts.status,
+        headers: parts.headers,
+        body: body.map_ok(|chunk| chunk.to_vec()).err_into().boxed(),
+    })
+}
+
+/// Checks the scheme and the host of the URL, and the addresses the host resolves to.
+/// Returns the host.
+async fn check_url(policy: &FetchPolicy, url: &Url) -> Result<String, Error> {
+    if !policy.schemes.iter().any(|s| s == url.scheme()) {
+        return Err(Error::Scheme(url.scheme().to_string()));
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::InvalidUrl("missing host".to_string()))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase();
+    let denied = policy.denied_hosts.iter().any(|h| host_matches(&host, h));
+    let allowed = policy.allowed_hosts.is_empty()
+        || policy.allowed_hosts.iter().any(|h| host_matches(&host, h));
+    if denied || !allowed {
+        return Err(Error::Host(host));
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let name = host.clone();
+    let addrs: Vec<SocketAddr> =
+        tokio_executor::blocking::run(move || (name.as_str(), port).to_socket_addrs())
+            .await
+            .map_err(Error::Resolve)?
+            .collect();
+    for addr in addrs {
+        check_ip(policy, addr.ip())?;
+    }
+
+    Ok(host)
+}
+
+fn check_ip(policy: &FetchPolicy, ip: IpAddr) -> Result<(), Error> {
+    if policy.allow_private_ips || is_public(ip) {
+        Ok(())
+    } else {
+        Err(Error::PrivateAddress(ip))
+    }
+}
+
+/// Whether `host` is `pattern` or one of its subdomains.
+fn host_matches(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim_start_matches('.').to_lowercase();
+    host == pattern || host.ends_with(&format!(".{}", pattern))
+}
+
+fn content_type_allowed(policy: &FetchPolicy, content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    policy.content_types.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+        if allowed.ends_with("/*") {
+            essence.starts_with(&allowed[..allowed.len() - 1])
+        } else {
+            essence == allowed
+        }
+    })
+}
+
+/// Whether the address is globally routable: not loopback, private, link-local (which includes
+/// cloud metadata endpoints), shared, multicast, reserved or unspecified.
+pub(crate) fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        // IPv4-mapped and -compatible addresses reach the embedded IPv4 address.
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        || (a == 100 && b & 0xc0 == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && b & 0xfe == 18)
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || first & 0xfe00 == 0xfc00
+        || first & 0xffc0 == 0xfe80
+        || first == 0x2001 && ip.segments()[1] == 0x0db8)
+}
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    InvalidUrl(String),
+    Scheme(String),
+    Host(String),
+    Resolve(std::io::Error),
+    PrivateAddress(IpAddr),
+    TooManyRedirects(usize),
+    Status(u16),
+    ContentType(String),
+    TooLarge(u64),
+    Timeout,
+    Hyper(hyper::Error),
+    Reqwest(reqwest::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::InvalidUrl(err) => write!(f, "invalid url: {}", err),
+            Error::Scheme(scheme) => write!(f, "scheme {} is not allowed", scheme),
+            Error::Host(host) => write!(f, "host {} is not allowed", host),
+            Error::Resolve(err) => write!(f, "failed to resolve host: {}", err),
+            Error::PrivateAddress(ip) => write!(f, "address {} is not public", ip),
+            Error::TooManyRedirects(max) => write!(f, "more than {} redirects", max),
+            Error::Status(status) => write!(f, "remote responded with status {}", status),
+            Error::ContentType(t) => write!(f, "content type {:?} is not accepted", t),
+            Error::TooLarge(max) => write!(f, "response is larger than {} bytes", max),
+            Error::Timeout => write!(f, "timed out"),
+            Error::Hyper(err) => write!(f, "failed to fetch: {}", err),
+            Error::Reqwest(err) => write!(f, "failed to fetch: {}", err),
+        }
+    }
+}
+
+impl Error {
+    /// Whether the request failed to connect or timed out, for another reason than the policy.
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Hyper(err) => is_failed_connect(err),
+            Error::Reqwest(err) => {
+                err.is_timeout()
+                    || sources(err)
+                        .filter_map(|err| err.downcast_ref::<hyper::Error>())
+                        .any(is_failed_connect)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the connection failed, but not because `CheckedResolver` denied the host.
+fn is_failed_connect(err: &hyper::Error) -> bool {
+    err.is_connect()
+        && !sources(err).any(|err| {
+            err.downcast_ref::<std::io::Error>().map_or(false, |err| {
+                err.kind() == std::io::ErrorKind::PermissionDenied
+            })
+        })
+}
+
+/// The error and the ones that caused it.
+fn sources<'a>(
+    err: &'a (dyn std::error::Error + 'static),
+) -> impl Iterator<Item = &'a (dyn std::error::Error + 'static)> {
+    std::iter::successors(Some(err), |err| err.source())
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Reqwest(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Self::Hyper(err)
+    }
+}
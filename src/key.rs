@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Longest accepted image name, in bytes. Matches the file name limit of common file systems.
+const MAX_NAME_LEN: usize = 255;
+
+/// Longest accepted storage key, in bytes.
+const MAX_KEY_LEN: usize = 1024;
+
+/// Crockford's base32 alphabet used by ULIDs.
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// How the server names images uploaded without a name.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum IdScheme {
+    /// Lexicographically sortable unique ID, see https://github.com/ulid/spec.
+    Ulid,
+    /// Hex encoded SHA-256 of the uploaded data, so equal uploads get equal names.
+    Hash,
+}
+
+impl Default for IdScheme {
+    fn default() -> Self {
+        IdScheme::Ulid
+    }
+}
+
+/// Validated storage key: a relative `/`-separated path that can't escape the storage root.
+///
+/// Keys coming from clients are image names, a single path component, which must not start with
+/// a dot since those are reserved for the internal folders. Internal keys are derived from names.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct ImageKey(String);
+
+impl ImageKey {
+    /// Parses an image name supplied by a client.
+    pub(crate) fn parse_name(name: &str) -> Result<Self, KeyError> {
+        if name.is_empty() {
+            return Err(KeyError("must not be empty"));
+        }
+
+        Self::parse_name_prefix(name)
+    }
+
+    /// Parses a client supplied prefix of image names, which may be empty.
+    pub(crate) fn parse_name_prefix(prefix: &str) -> Result<Self, KeyError> {
+        if prefix.len() > MAX_NAME_LEN {
+            return Err(KeyError("must be at most 255 bytes long"));
+        }
+
+        if prefix.contains('/') || prefix.contains('\\') {
+            return Err(KeyError("must not contain path separators"));
+        }
+
+        if prefix.starts_with('.') {
+            return Err(KeyError("must not start with a dot"));
+        }
+
+        check_chars(prefix)?;
+        Ok(Self(prefix.to_string()))
+    }
+
+    /// Makes a server assigned image name: `{id}.{extension}`.
+    pub(crate) fn generate(scheme: IdScheme, data: &[u8], extension: &str) -> Self {
+        let id = match scheme {
+            IdScheme::Ulid => ulid(),
+            IdScheme::Hash => hex::encode(Sha256::digest(data)),
+        };
+
+        Self(format!("{}.{}", id, extension))
+    }
+
+    /// Name with a counter inserted before the extension: `img.jpeg` becomes `img-{n}.jpeg`.
+    pub(crate) fn with_counter(&self, n: u32) -> Result<Self, KeyError> {
+        let name = self.0.as_str();
+        let (stem, extension) = match name.rfind('.') {
+            Some(i) => name.split_at(i),
+            None => (name, ""),
+        };
+
+        Self::parse_name(&format!("{}-{}{}", stem, n, extension))
+    }
+
+    /// Parses a storage key, e.g. one returned by a storage listing.
+    /// The last component may be empty, making the key a folder prefix.
+    pub(crate) fn parse(key: &str) -> Result<Self, KeyError> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KeyError("must be at most 1024 bytes long"));
+        }
+
+        if key.contains('\\') {
+            return Err(KeyError("must not contain backslashes"));
+        }
+
+        let components: Vec<_> = key.split('/').collect();
+        let (last, parents) = components.split_last().expect("split yields a component");
+        if parents.iter().any(|c| c.is_empty()) {
+            return Err(KeyError("must be a relative path without empty components"));
+        }
+
+        if components.iter().any(|&c| c == "." || c == "..") {
+            return Err(KeyError("must not contain relative path components"));
+        }
+
+        if last.len() > MAX_NAME_LEN {
+            return Err(KeyError(
+                "must not contain components longer than 255 bytes",
+            ));
+        }
+
+        check_chars(key)?;
+        Ok(Self(key.to_string()))
+    }
+
+    /// Key of an object kept for this image in an internal folder: `{folder}/{name}{suffix}`.
+    pub(crate) fn derived(&self, folder: &str, suffix: &str) -> Self {
+        Self(format!("{}/{}{}", folder, self.0, suffix))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ImageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// 48 bits of milliseconds since the epoch followed by 80 random bits, base32 encoded.
+pub(crate) fn ulid() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after epoch")
+        .as_millis();
+    let random = rand::random::<u128>() & ((1 << 80) - 1);
+    let value = ((millis & ((1 << 48) - 1)) << 80) | random;
+
+    (0..26)
+        .map(|i| ULID_ALPHABET[((value >> (125 - 5 * i)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+fn check_chars(s: &str) -> Result<(), KeyError> {
+    if s.chars().any(char::is_control) {
+        return Err(KeyError("must not contain control characters"));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct KeyError(&'static str);
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -3,43 +3,466 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
+use serde::{Deserialize, Serialize};
 use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
 use std::ptr;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 const NULL: *const c_void = ptr::null_mut();
 
-pub(crate) fn thumbnail(mut img: Vec<u8>) -> Result<Vec<u8>, Error> {
+/// Thumbnail box used when a request specifies neither width nor height.
+const DEFAULT_SIZE: c_int = 100;
+
+/// Same as `VIPS_MAX_COORD`, used to leave one side of the thumbnail box unconstrained.
+const UNBOUNDED: c_int = 10_000_000;
+
+macro_rules! opt {
+    ($name:expr) => {
+        concat!($name, "\0").as_ptr() as *const c_char
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Crop {
+    None,
+    Centre,
+    Attention,
+    Entropy,
+}
+
+impl Default for Crop {
+    fn default() -> Self {
+        Crop::None
+    }
+}
+
+impl Crop {
+    fn as_vips(self) -> VipsInteresting {
+        match self {
+            Crop::None => VipsInteresting_VIPS_INTERESTING_NONE,
+            Crop::Centre => VipsInteresting_VIPS_INTERESTING_CENTRE,
+            Crop::Attention => VipsInteresting_VIPS_INTERESTING_ATTENTION,
+            Crop::Entropy => VipsInteresting_VIPS_INTERESTING_ENTROPY,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Size {
+    Up,
+    Down,
+    Both,
+    Force,
+}
+
+impl Default for Size {
+    fn default() -> Self {
+        Size::Both
+    }
+}
+
+impl Size {
+    fn as_vips(self) -> VipsSize {
+        match self {
+            Size::Up => VipsSize_VIPS_SIZE_UP,
+            Size::Down => VipsSize_VIPS_SIZE_DOWN,
+            Size::Both => VipsSize_VIPS_SIZE_BOTH,
+            Size::Force => VipsSize_VIPS_SIZE_FORCE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Format {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Jpeg
+    }
+}
+
+impl Format {
+    /// Tells the format from the magic bytes at the start of the data.
+    pub(crate) fn detect(data: &[u8]) -> Option<Self> {
+        match InputFormat::detect(data)? {
+            InputFormat::Jpeg => Some(Format::Jpeg),
+            InputFormat::Png => Some(Format::Png),
+            InputFormat::Webp => Some(Format::Webp),
+            InputFormat::Avif => Some(Format::Avif),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+            Format::Webp => "image/webp",
+            Format::Avif => "image/avif",
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpeg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+
+    /// Range of the compression effort accepted by the format saver, if it has one.
+    pub(crate) fn effort_range(self) -> Option<(u8, u8)> {
+        match self {
+            Format::Jpeg => None,
+            Format::Png => Some((0, 9)),
+            Format::Webp => Some((0, 6)),
+            Format::Avif => Some((0, 9)),
+        }
+    }
+
+    pub(crate) fn supports_lossless(self) -> bool {
+        self == Format::Webp || self == Format::Avif
+    }
+}
+
+/// Format of uploaded images, told by the magic bytes rather than the name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+    Heif,
+    Avif,
+    Tiff,
+}
+
+impl InputFormat {
+    /// Number of leading bytes `detect` needs at most.
+    pub(crate) const MAGIC_LEN: usize = 12;
+
+    /// Tells the format from the magic bytes at the start of the data.
+    pub(crate) fn detect(data: &[u8]) -> Option<Self> {
+        // ISO base media files (HEIF, AVIF) start with an `ftyp` box naming the major brand.
+        let brand = if data.len() >= 12 && &data[4..8] == b"ftyp" {
+            &data[8..12]
+        } else {
+            &[]
+        };
+
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(InputFormat::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(InputFormat::Png)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(InputFormat::Webp)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(InputFormat::Gif)
+        } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+            Some(InputFormat::Tiff)
+        } else if brand == b"avif" || brand == b"avis" {
+            Some(InputFormat::Avif)
+        } else if [
+            b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+        ]
+        .iter()
+        .any(|b| &b[..] == brand)
+        {
+            Some(InputFormat::Heif)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            InputFormat::Jpeg => "jpeg",
+            InputFormat::Png => "png",
+            InputFormat::Webp => "webp",
+            InputFormat::Gif => "gif",
+            InputFormat::Heif => "heif",
+            InputFormat::Avif => "avif",
+            InputFormat::Tiff => "tiff",
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            InputFormat::Jpeg => "image/jpeg",
+            InputFormat::Png => "image/png",
+            InputFormat::Webp => "image/webp",
+            InputFormat::Gif => "image/gif",
+            InputFormat::Heif => "image/heif",
+            InputFormat::Avif => "image/avif",
+            InputFormat::Tiff => "image/tiff",
+        }
+    }
+}
+
+/// Output format and saver options. Unset values fall back to the libvips defaults.
+pub(crate) struct Encoding {
+    pub(crate) format: Format,
+    pub(crate) quality: Option<u8>,
+    pub(crate) lossless: bool,
+    pub(crate) interlace: bool,
+    pub(crate) effort: Option<u8>,
+}
+
+impl Encoding {
+    fn quality(&self, default: c_int) -> c_int {
+        self.quality.map(c_int::from).unwrap_or(default)
+    }
+
+    fn effort(&self, default: c_int) -> c_int {
+        self.effort.map(c_int::from).unwrap_or(default)
+    }
+
+    /// The `speed` option of the heif saver before libvips 8.12, which runs the other way than
+    /// `effort` and stops at 8.
+    fn heif_speed(&self) -> c_int {
+        (9 - self.effort(4)).min(8)
+    }
+}
+
+/// Whether the webp and heif savers take `effort`, added in libvips 8.12. Older versions call
+/// it `reduction_effort` (webp) and `speed` (heif).
+fn has_effort_option() -> bool {
+    let version = unsafe { (vips_version(0), vips_version(1)) };
+    version >= (8, 12)
+}
+
+/// Target box of a thumbnail. A missing side is left unconstrained, unless the image is cropped,
+/// in which case the box becomes a square.
+pub(crate) struct Geometry {
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) crop: Crop,
+    pub(crate) size: Size,
+}
+
+impl Geometry {
+    fn resolve(&self) -> (c_int, c_int) {
+        let unbounded = if self.crop == Crop::None {
+            None
+        } else {
+            self.width.or(self.height)
+        }
+        .map(|v| v as c_int)
+        .unwrap_or(UNBOUNDED);
+
+        match (self.width, self.height) {
+            (Some(w), Some(h)) => (w as c_int, h as c_int),
+            (Some(w), None) => (w as c_int, unbounded),
+            (None, Some(h)) => (unbounded, h as c_int),
+            (None, None) => (DEFAULT_SIZE, DEFAULT_SIZE),
+        }
+    }
+}
+
+pub(crate) fn thumbnail(
+    mut img: Vec<u8>,
+    geometry: &Geometry,
+    encoding: &Encoding,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = geometry.resolve();
     let src = img.as_mut_ptr();
     let len = img.len();
     let mut vips_img: *mut VipsImage = ptr::null_mut();
-    if unsafe { vips_thumbnail_buffer(src as *mut c_void, len, &mut vips_img, 100, NULL) } != 0 {
-        return Err(Error::new("vips_thumbnail_buffer failed"));
+    if unsafe {
+        vips_thumbnail_buffer(
+            src as *mut c_void,
+            len,
+            &mut vips_img,
+            width,
+            opt!("height"),
+            height,
+            opt!("crop"),
+            geometry.crop.as_vips(),
+            opt!("size"),
+            geometry.size.as_vips(),
+            NULL,
+        )
+    } != 0
+    {
+        return Err(Error::load("vips_thumbnail_buffer failed"));
     };
 
+    let res = save(vips_img, encoding);
+    unsafe { g_object_unref(vips_img as gpointer) };
+    res
+}
+
+/// Image properties read from the header, without decoding the pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Header {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) bands: u32,
+    /// Frames of animated images or pages of multi-page documents, 1 for plain images.
+    pub(crate) pages: u32,
+    /// Nickname of the `VipsInterpretation`, e.g. `srgb` or `b-w`.
+    pub(crate) color_space: String,
+    pub(crate) has_alpha: bool,
+}
+
+pub(crate) fn header(img: &[u8]) -> Result<Header, Error> {
+    let vips_img = unsafe {
+        vips_image_new_from_buffer(img.as_ptr() as *const c_void, img.len(), opt!(""), NULL)
+    };
+    if vips_img.is_null() {
+        return Err(Error::load("vips_image_new_from_buffer failed"));
+    }
+
+    let header = unsafe {
+        let interpretation = vips_image_get_interpretation(vips_img);
+        let nick = vips_enum_nick(vips_interpretation_get_type(), interpretation as c_int);
+        Header {
+            width: vips_image_get_width(vips_img) as u32,
+            height: vips_image_get_height(vips_img) as u32,
+            bands: vips_image_get_bands(vips_img) as u32,
+            pages: vips_image_get_n_pages(vips_img).max(1) as u32,
+            color_space: if nick.is_null() {
+                String::default()
+            } else {
+                CStr::from_ptr(nick).to_string_lossy().into_owned()
+            },
+            has_alpha: vips_image_hasalpha(vips_img) != 0,
+        }
+    };
+    unsafe { g_object_unref(vips_img as gpointer) };
+    Ok(header)
+}
+
+fn save(img: *mut VipsImage, enc: &Encoding) -> Result<Vec<u8>, Error> {
     let mut len: usize = 0;
     let mut buf: *mut c_void = ptr::null_mut();
-    if unsafe { vips_jpegsave_buffer(vips_img, &mut buf, &mut len, NULL) } != 0 {
-        return Err(Error::new("vips_jpegsave_buffer failed"));
+    let interlace = enc.interlace as c_int;
+    let lossless = enc.lossless as c_int;
+    let (webp_effort, heif_effort, heif_effort_value) = if has_effort_option() {
+        (opt!("effort"), opt!("effort"), enc.effort(4))
+    } else {
+        (opt!("reduction_effort"), opt!("speed"), enc.heif_speed())
+    };
+    let (res, saver) = unsafe {
+        match enc.format {
+            Format::Jpeg => (
+                vips_jpegsave_buffer(
+                    img,
+                    &mut buf,
+                    &mut len,
+                    opt!("Q"),
+                    enc.quality(75),
+                    opt!("interlace"),
+                    interlace,
+                    NULL,
+                ),
+                "vips_jpegsave_buffer",
+            ),
+            Format::Png => (
+                vips_pngsave_buffer(
+                    img,
+                    &mut buf,
+                    &mut len,
+                    opt!("compression"),
+                    enc.effort(6),
+                    opt!("interlace"),
+                    interlace,
+                    NULL,
+                ),
+                "vips_pngsave_buffer",
+            ),
+            Format::Webp => (
+                vips_webpsave_buffer(
+                    img,
+                    &mut buf,
+                    &mut len,
+                    opt!("Q"),
+                    enc.quality(75),
+                    opt!("lossless"),
+                    lossless,
+                    webp_effort,
+                    enc.effort(4),
+                    NULL,
+                ),
+                "vips_webpsave_buffer",
+            ),
+            Format::Avif => (
+                vips_heifsave_buffer(
+                    img,
+                    &mut buf,
+                    &mut len,
+                    opt!("Q"),
+                    enc.quality(50),
+                    opt!("lossless"),
+                    lossless,
+                    opt!("compression"),
+                    VipsForeignHeifCompression_VIPS_FOREIGN_HEIF_COMPRESSION_AV1,
+                    heif_effort,
+                    heif_effort_value,
+                    NULL,
+                ),
+                "vips_heifsave_buffer",
+            ),
+        }
+    };
+    if res != 0 {
+        return Err(Error::new(&format!("{} failed", saver)));
     };
 
-    Ok(unsafe { Vec::from_raw_parts(buf as *mut u8, len, len) })
+    Ok(unsafe { take_buffer(buf, len) })
+}
+
+/// Copies a buffer allocated by libvips into a `Vec` and releases the original.
+unsafe fn take_buffer(buf: *mut c_void, len: usize) -> Vec<u8> {
+    let data = std::slice::from_raw_parts(buf as *const u8, len).to_vec();
+    g_free(buf as gpointer);
+    data
 }
 
 #[derive(Debug)]
-pub(crate) struct Error(String);
+pub(crate) struct Error {
+    details: String,
+    load: bool,
+}
 
 impl Error {
     fn new(details: &str) -> Self {
-        Self(format!("{}:{}", details, unsafe {
-            CStr::from_ptr(vips_error_buffer()).to_string_lossy()
-        }))
+        Self {
+            details: format!("{}:{}", details, unsafe {
+                CStr::from_ptr(vips_error_buffer()).to_string_lossy()
+            }),
+            load: false,
+        }
+    }
+
+    /// Error of an operation reading the input image.
+    fn load(details: &str) -> Self {
+        Self {
+            load: true,
+            ..Self::new(details)
+        }
+    }
+
+    /// Tells if the input image could not be read, i.e. the data is broken rather than libvips.
+    pub(crate) fn is_load(&self) -> bool {
+        self.load
     }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.details)
     }
 }
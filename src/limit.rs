@@ -0,0 +1,111 @@
+use futures::channel::oneshot;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::timer::delay_for;
+
+/// How often `wait_until_done` checks whether the tasks are done.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bounds how many tasks do something at once, like a semaphore. Waiters are served in the order
+/// they arrived, and a waiter that gives up never takes a permit with it.
+#[derive(Clone)]
+pub(crate) struct Limiter(Arc<Mutex<State>>);
+
+struct State {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(permits: usize) -> Self {
+        Limiter(Arc::new(Mutex::new(State {
+            available: permits,
+            waiters: VecDeque::new(),
+        })))
+    }
+
+    pub(crate) async fn acquire(&self) -> Permit {
+        let rx = {
+            let mut state = self.0.lock().expect("lock limiter");
+            if state.available > 0 {
+                state.available -= 1;
+                return Permit(Some(self.0.clone()));
+            }
+
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+            rx
+        };
+
+        // The sender is only dropped along with the limiter, which `self` keeps alive.
+        rx.await.expect("limiter alive")
+    }
+
+    /// Whether no permit is held or awaited.
+    pub(crate) fn is_idle(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+}
+
+/// Waits until `pending` counts no `what` left, logging once what it's waiting for. For graceful
+/// shutdowns, so it polls instead of being notified.
+pub(crate) async fn wait_until_done<F>(what: &str, pending: F)
+where
+    F: Fn() -> usize,
+{
+    let mut logged = false;
+    loop {
+        let pending = pending();
+        if pending == 0 {
+            return;
+        }
+
+        if !logged {
+            info!("waiting for {} {}", pending, what);
+            logged = true;
+        }
+        delay_for(DRAIN_INTERVAL).await;
+    }
+}
+
+/// Delay before the retry following `attempt` (from 0): `base_ms`, doubled for every further one.
+pub(crate) fn backoff(base_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(base_ms.saturating_mul(1 << attempt.min(16)))
+}
+
+/// Held while doing the limited thing, handed on to the next waiter when dropped.
+pub(crate) struct Permit(Option<Arc<Mutex<State>>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let limiter = match self.0.take() {
+            Some(limiter) => limiter,
+            None => return,
+        };
+
+        // The lock isn't held while sending: a permit that comes back is dropped on this thread,
+        // and must not find the lock taken.
+        loop {
+            let tx = {
+                let mut state = limiter.lock().expect("lock limiter");
+                match state.waiters.pop_front() {
+                    Some(tx) => tx,
+                    None => {
+                        state.available += 1;
+                        return;
+                    }
+                }
+            };
+
+            // A permit sent to a waiter that gave up in the meantime is dropped with the channel
+            // and released again, one refused right away is disarmed and offered to the next.
+            match tx.send(Permit(Some(limiter.clone()))) {
+                Ok(()) => return,
+                Err(mut permit) => {
+                    permit.0.take();
+                }
+            }
+        }
+    }
+}
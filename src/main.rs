@@ -1,7 +1,15 @@
 mod api;
+mod auth;
+mod config;
+mod fetch;
+mod key;
 mod libvips;
+mod limit;
+mod range;
 mod service;
+mod storage;
 mod tests;
+mod webhook;
 
 #[macro_use]
 extern crate log;
@@ -17,6 +25,10 @@ use tokio_net::signal::unix::{signal, SignalKind};
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
+    lazy_static::initialize(&config::CONFIG);
+    if config::CONFIG.auth.open {
+        warn!("auth.open is set, every endpoint is open");
+    }
 
     let (tx, mut rx) = tokio::sync::mpsc::channel::<()>(1);
     tokio_executor::spawn(notify_shutdown(tx));
@@ -34,6 +46,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     info!("listening on {}", addr);
     server.await?;
+    api::jobs::drain().await;
+    webhook::drain().await;
     Ok(())
 }
 
@@ -0,0 +1,85 @@
+use std::ops::Range;
+
+/// Upper bound for the number of ranges of a request, the whole image is sent for more.
+const MAX_RANGES: usize = 16;
+
+/// Outcome of evaluating a `Range` header against the size of the representation.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Ranges {
+    /// Send the whole representation, the header is ignored.
+    Full,
+    /// Send the byte ranges, as half-open intervals in the requested order.
+    Partial(Vec<Range<u64>>),
+    /// None of the ranges can be satisfied.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header (RFC 7233). Only the `bytes` unit is understood, headers with other
+/// units are ignored.
+pub(crate) fn parse(header: &str, size: u64) -> Ranges {
+    let header = header.trim();
+    if !header.starts_with("bytes=") {
+        return Ranges::Full;
+    }
+
+    let specs: Vec<_> = header["bytes=".len()..]
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if specs.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        match parse_spec(spec, size) {
+            Ok(Some(range)) => ranges.push(range),
+            Ok(None) => {}
+            // Invalid specs make the whole header invalid, which is ignored like an unknown unit.
+            Err(()) => return Ranges::Full,
+        }
+    }
+
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+/// Parses `first-last`, `first-` or `-suffix_length`. Returns `None` for a valid range lying
+/// outside of the representation, and an error for an invalid spec, e.g. one ending before it
+/// starts.
+fn parse_spec(spec: &str, size: u64) -> Result<Option<Range<u64>>, ()> {
+    let dash = spec.find('-').ok_or(())?;
+    let (first, last) = (&spec[..dash], &spec[dash + 1..]);
+    let number = |s: &str| s.parse::<u64>().map_err(|_| ());
+
+    if first.is_empty() {
+        let suffix = number(last)?;
+        if suffix == 0 || size == 0 {
+            return Ok(None);
+        }
+
+        return Ok(Some(size - suffix.min(size)..size));
+    }
+
+    let first = number(first)?;
+    let last = match last {
+        "" => None,
+        last => Some(number(last)?),
+    };
+
+    match last {
+        Some(last) if last < first => Err(()),
+        _ if first >= size => Ok(None),
+        Some(last) => Ok(Some(first..last.saturating_add(1).min(size))),
+        None => Ok(Some(first..size)),
+    }
+}
+
+/// Value of the `Content-Range` header of a part.
+pub(crate) fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
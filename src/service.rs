@@ -1,84 +1,1206 @@
+use super::config::CONFIG;
+use super::fetch;
+use super::key::{self, ImageKey, KeyError};
 use super::libvips;
-use std::io::ErrorKind::NotFound as IONotFound;
-use std::path::Path;
-use tokio::{fs::File, prelude::*};
+use super::limit::Limiter;
+use super::storage::{self, Storage};
+use futures::stream::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+/// Upper bound for the requested thumbnail width and height.
+const MAX_DIMENSION: u32 = 10_000;
+
+/// Folder (relative to the storage root) holding derivatives rendered on demand.
+const CACHE_FOLDER: &str = ".cache";
+
+/// Folder (relative to the storage root) holding the untouched uploads.
+const ORIGINALS_FOLDER: &str = ".originals";
+
+/// Folder (relative to the storage root) holding a `Record` per stored image.
+const RECORDS_FOLDER: &str = ".meta";
+
+/// Folder (relative to the storage root) holding the data shared by uploads with equal content,
+/// see `BlobRef`.
+const BLOBS_FOLDER: &str = ".blobs";
+
+/// Name of the untouched upload in a blob folder.
+const ORIGINAL_BLOB: &str = "original";
+
+/// Folder (relative to the storage root) holding copies of replaced images, see `Backup`.
+const REPLACED_FOLDER: &str = ".replaced";
+
+/// Name of the thumbnail in a backup folder.
+const THUMBNAIL_BACKUP: &str = "thumbnail";
+
+/// Name of the original in a backup folder.
+const ORIGINAL_BACKUP: &str = "original";
+
+/// Name of the `BlobRefs` in a blob folder.
+const BLOB_REFS: &str = "refs.json";
+
+/// Number of objects requested per page when walking over a storage folder.
+const LIST_PAGE_SIZE: usize = 100;
+
+/// Highest counter tried when looking for a free name with `ConflictPolicy::Suffix`.
+const MAX_NAME_COUNTER: u32 = 1000;
+
+lazy_static! {
+    /// Serializes read-modify-write cycles of records.
+    static ref RECORDS_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Names of images being stored which must not be taken by concurrent uploads.
+    static ref CLAIMED_KEYS: std::sync::Mutex<HashSet<ImageKey>> =
+        std::sync::Mutex::new(HashSet::new());
+
+    /// Serializes the writers of each image and of each cached derivative, entries are dropped
+    /// once idle.
+    static ref WRITERS: std::sync::Mutex<HashMap<ImageKey, Limiter>> =
+        std::sync::Mutex::new(HashMap::new());
+
+    /// Bounds the libvips work of all requests, so they can't oversubscribe CPU and memory.
+    static ref VIPS: Limiter = Limiter::new(CONFIG.processing.vips_concurrency);
+}
+
+/// What to do when an image with the requested name already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConflictPolicy {
+    /// Replace the existing image.
+    Overwrite,
+    /// Fail with `ErrorKind::Conflict`.
+    Reject,
+    /// Store under the first free name of `img-1.jpeg`, `img-2.jpeg`, ...
+    Suffix,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Overwrite
+    }
+}
+
+/// Exclusive right to store an image under a name, released on drop.
+struct Claim(Option<ImageKey>);
+
+impl Claim {
+    /// Claims `key`, or a free variation of it, according to the policy.
+    async fn new(
+        key: &ImageKey,
+        storage: &dyn Storage,
+        policy: ConflictPolicy,
+    ) -> Result<(Self, ImageKey), Error> {
+        if policy == ConflictPolicy::Overwrite {
+            return Ok((Claim(None), key.clone()));
+        }
+
+        for n in 0..=MAX_NAME_COUNTER {
+            let candidate = match n {
+                0 => key.clone(),
+                _ => key
+                    .with_counter(n)
+                    .map_err(|e| Error::invalid_argument("filename", &e.to_string(), e))?,
+            };
+
+            if let Some(claim) = Claim::try_new(&candidate, storage).await? {
+                return Ok((claim, candidate));
+            }
+
+            if policy == ConflictPolicy::Reject {
+                break;
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::Conflict,
+            ErrorCause::Conflict(key.clone()),
+        ))
+    }
+
+    async fn try_new(key: &ImageKey, storage: &dyn Storage) -> Result<Option<Self>, Error> {
+        let claimed = CLAIMED_KEYS.lock().expect("lock keys").insert(key.clone());
+        if !claimed {
+            return Ok(None);
+        }
+
+        let claim = Claim(Some(key.clone()));
+        match storage.head(key).await {
+            Err(ref err) if err.is_not_found() => Ok(Some(claim)),
+            Err(err) => Err(Error::from(err).context("check name")),
+            Ok(_) => Ok(None),
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(key) = &self.0 {
+            CLAIMED_KEYS.lock().expect("lock keys").remove(key);
+        }
+    }
+}
+
+/// Tells if the derivative at `path` was rendered already.
+async fn is_cached(path: &ImageKey, storage: &dyn Storage) -> Result<bool, Error> {
+    match storage.head(path).await {
+        Err(ref err) if err.is_not_found() => Ok(false),
+        res => res.map(|_| true).context("find cached derivative"),
+    }
+}
+
+/// Limiter letting one task at a time store or delete the image, or render the derivative.
+fn writer(key: &ImageKey) -> Limiter {
+    let mut writers = WRITERS.lock().expect("lock writers");
+    writers.retain(|_, limiter| !limiter.is_idle());
+    writers
+        .entry(key.clone())
+        .or_insert_with(|| Limiter::new(1))
+        .clone()
+}
+
+/// Parses an image name supplied by a client.
+pub(crate) fn image_key(name: &str) -> Result<ImageKey, Error> {
+    ImageKey::parse_name(name).map_err(|e| Error::invalid_argument("filename", &e.to_string(), e))
+}
+
+/// Downloads image data from a remote source allowed by the fetch policy.
+pub(crate) async fn fetch_remote(uri: String) -> Result<Vec<u8>, Error> {
+    fetch::fetch(&CONFIG.fetch, &uri).await.map_err(|err| {
+        let details = err.to_string();
+        Error::invalid_argument("uri", &details, ErrorCause::Fetch(err))
+    })
+}
+
+pub(crate) async fn decode_base64(data: String) -> Result<Vec<u8>, Error> {
+    tokio_executor::blocking::run(move || base64::decode(&data))
+        .await
+        .or_invalid_argument("base64", "failed to decode")
+}
 
 pub(crate) struct Image {
-    pub(crate) filename: String,
+    pub(crate) key: ImageKey,
     pub(crate) data: Vec<u8>,
 }
 
 impl Image {
-    pub(crate) fn new(filename: String, data: Vec<u8>) -> Self {
+    pub(crate) fn new(key: ImageKey, data: Vec<u8>) -> Self {
         Image {
-            filename: filename,
+            key: key,
             data: data,
         }
     }
 
-    pub(crate) async fn from_remote_source(filename: String, uri: String) -> Result<Self, Error> {
-        let client = reqwest::Client::new();
-        let res = client
-            .get(&uri)
-            .send()
-            .await
-            .or_invalid_argument("uri", "failed to fetch specified file")?;
+    pub(crate) async fn from_storage(key: ImageKey, storage: &dyn Storage) -> Result<Self, Error> {
+        let data = storage.get(&key).await.context("read file")?;
+        Ok(Image::new(key, data))
+    }
 
-        let body = res
-            .bytes()
+    pub(crate) async fn original(key: ImageKey, storage: &dyn Storage) -> Result<Self, Error> {
+        let data = storage
+            .get(&key.derived(ORIGINALS_FOLDER, ""))
             .await
-            .or_internal_err()
-            .context("get response bytes")?;
+            .context("read original")?;
+        Ok(Image::new(key, data))
+    }
+
+    /// Stores the untouched upload next to the thumbnail made from it and records both.
+    /// Uploads are deduplicated by content: if the same data was already thumbnailed with the
+    /// same options, the stored blobs are shared instead of rendering them again.
+    /// The name actually used depends on the conflict policy. Derivatives cached for a previous
+    /// image with the same name are dropped, the image itself is kept until the store is committed
+    /// or rolled back, see `Stored`. Stores and deletes of the same name are serialized.
+    pub(crate) async fn store(
+        self,
+        storage: &dyn Storage,
+        opts: &ThumbnailOptions,
+        policy: ConflictPolicy,
+    ) -> Result<Stored, Error> {
+        let input_format = check_input_format(&self.data)?;
+        let (_claim, key) = Claim::new(&self.key, storage, policy).await?;
+        let _writer = writer(&key).acquire().await;
+        let blob = BlobRef {
+            hash: content_hash(&self.data),
+            variant: opts.variant_name(),
+        };
+
+        let deduplicated = {
+            let _lock = RECORDS_LOCK.lock().await;
+            blob.acquire(storage, None).await?
+        };
+
+        let thumb = if deduplicated {
+            None
+        } else {
+            let thumb = Image::new(key.clone(), self.data.clone())
+                .into_thumbnail(opts)
+                .await
+                .context("thumbnail img")?;
+
+            let _lock = RECORDS_LOCK.lock().await;
+            blob.acquire(storage, Some((self.data, thumb.data.clone())))
+                .await?;
+            Some(thumb.data)
+        };
 
-        Ok(Self::new(filename, body.to_vec()))
+        // From here on, a failure drops the reference taken above again.
+        let backup = match Backup::new(&key, storage).await {
+            Ok(backup) => backup,
+            Err(err) => {
+                blob.abandon(storage).await;
+                return Err(err);
+            }
+        };
+        let stored = Stored {
+            key: key,
+            deduplicated: deduplicated,
+            replaced: backup.is_some(),
+            input_format: input_format,
+            blob: blob,
+            backup: backup,
+        };
+
+        if let Err(err) = Image::put(&stored.key, &stored.blob, thumb, opts, storage).await {
+            let key = stored.key.clone();
+            if let Err(err) = stored.restore(storage).await {
+                error!("restore {}: {}", key, err);
+            }
+            return Err(err);
+        }
+
+        Ok(stored)
     }
 
-    pub(crate) async fn from_base64(filename: String, data: String) -> Result<Self, Error> {
-        let bytes = tokio_executor::blocking::run(move || base64::decode(&data))
+    /// Copies the blobs to the image, reading the thumbnail from its blob unless it was just
+    /// rendered, and records them.
+    async fn put(
+        key: &ImageKey,
+        blob: &BlobRef,
+        thumb: Option<Vec<u8>>,
+        opts: &ThumbnailOptions,
+        storage: &dyn Storage,
+    ) -> Result<(), Error> {
+        let data = match thumb {
+            Some(data) => data,
+            None => storage
+                .get(&blob.key(&blob.variant))
+                .await
+                .context("read thumbnail blob")?,
+        };
+        let info = Image::new(key.clone(), data)
+            .info(SystemTime::now())
+            .await?;
+
+        let original = key.derived(ORIGINALS_FOLDER, "");
+        storage
+            .copy(&blob.key(ORIGINAL_BLOB), &original)
+            .await
+            .context("save original")?;
+        storage
+            .copy(&blob.key(&blob.variant), key)
             .await
-            .or_invalid_argument("base64", "failed to decode")?;
+            .context("save thumbnail")?;
 
-        Ok(Self::new(filename, bytes))
+        let derivative = Derivative {
+            path: key.to_string(),
+            options: opts.clone(),
+            hash: Some(info.hash.clone()),
+        };
+        let record = Record {
+            original: original.to_string(),
+            blob: Some(blob.clone()),
+            info: Some(info),
+            derivatives: vec![derivative],
+        };
+
+        let _lock = RECORDS_LOCK.lock().await;
+        delete_folder(storage, &key.derived(CACHE_FOLDER, "/"))
+            .await
+            .context("drop cached derivatives")?;
+        record.save(key, storage).await
     }
 
-    pub(crate) async fn from_storage(filename: String, folder: &Path) -> Result<Self, Error> {
-        let mut data = Vec::new();
+    /// Deletes the image with everything kept for it: the original, the cached derivatives, the
+    /// record and its references to shared blobs. The thumbnail goes last, so a failed delete
+    /// can be retried.
+    pub(crate) async fn delete(key: &ImageKey, storage: &dyn Storage) -> Result<(), Error> {
+        let _writer = writer(key).acquire().await;
+        let _lock = RECORDS_LOCK.lock().await;
+        storage.head(key).await.context("find image")?;
 
-        let mut file = File::open(folder.join(&filename))
+        delete_existing(storage, &key.derived(ORIGINALS_FOLDER, ""))
+            .await
+            .context("delete original")?;
+        delete_folder(storage, &key.derived(CACHE_FOLDER, "/"))
             .await
-            .map_err(|e| Error::map_io(e, IONotFound, ErrorKind::NotFound))
-            .context("open file")?;
+            .context("delete cached derivatives")?;
+        if let Some(record) = Record::load(key, storage).await? {
+            if let Some(blob) = record.blob {
+                blob.release(storage).await.context("release blob")?;
+            }
 
-        file.read_to_end(&mut data)
+            delete_existing(storage, &key.derived(RECORDS_FOLDER, ".json"))
+                .await
+                .context("delete record")?;
+        }
+
+        delete_existing(storage, key)
+            .await
+            .context("delete thumbnail")
+    }
+
+    /// Renders and caches a derivative of the stored image, unless it's cached already.
+    /// Returns its storage key. Derivatives are rendered from the original if it was kept.
+    pub(crate) async fn cache_derivative(
+        key: &ImageKey,
+        storage: &dyn Storage,
+        opts: &ThumbnailOptions,
+    ) -> Result<ImageKey, Error> {
+        let path = key.derived(CACHE_FOLDER, &format!("/{}", opts.variant_name()));
+        if is_cached(&path, storage).await? {
+            return Ok(path);
+        }
+
+        // Concurrent requests for the same derivative render it once, the others find it cached.
+        let _writer = writer(&path).acquire().await;
+        if is_cached(&path, storage).await? {
+            return Ok(path);
+        }
+
+        // The image may be replaced or deleted while it's rendered, which drops its cache under
+        // the records lock. The render is only cached if the image is still the one it was made
+        // from, it's made again from the new one otherwise.
+        loop {
+            let blob = Record::load(key, storage).await?.and_then(|r| r.blob);
+            let src = match Image::original(key.clone(), storage).await {
+                Err(ref err) if err.is_not_found() => {
+                    Image::from_storage(key.clone(), storage).await?
+                }
+                res => res?,
+            };
+
+            let thumb = src
+                .into_thumbnail(opts)
+                .await
+                .context("render derivative")?;
+            let hash = content_hash(&thumb.data);
+
+            let _lock = RECORDS_LOCK.lock().await;
+            let record = Record::load(key, storage).await?;
+            if record.as_ref().and_then(|r| r.blob.as_ref()) != blob.as_ref() {
+                continue;
+            }
+
+            storage
+                .put(&path, thumb.data)
+                .await
+                .context("cache derivative")?;
+            if let Some(mut record) = record {
+                // Left over if the cached file was deleted behind our back.
+                record.derivatives.retain(|d| d.path != path.as_str());
+                record.derivatives.push(Derivative {
+                    path: path.to_string(),
+                    options: opts.clone(),
+                    hash: Some(hash),
+                });
+                record.save(key, storage).await?;
+            }
+
+            return Ok(path);
+        }
+    }
+
+    /// Lists the images whose names start with `prefix` in lexicographic order, starting after
+    /// `cursor`. Returns the page and the cursor of the next one, if there is more.
+    pub(crate) async fn list(
+        storage: &dyn Storage,
+        prefix: &ImageKey,
+        cursor: Option<ImageKey>,
+        limit: usize,
+    ) -> Result<(Vec<Listed>, Option<ImageKey>), Error> {
+        let mut objects = Vec::new();
+        let mut start_after = cursor;
+        // Internal objects (with names starting with a dot) are skipped, so a page may need
+        // several listings. One more image than requested tells if there is a next page.
+        while objects.len() <= limit {
+            let page = storage
+                .list(prefix, start_after.as_ref(), limit + 1)
+                .await
+                .context("list images")?;
+            if page.is_empty() {
+                break;
+            }
+
+            start_after = page.last().map(|obj| obj.key.clone());
+            objects.extend(
+                page.into_iter()
+                    .filter(|obj| ImageKey::parse_name(obj.key.as_str()).is_ok()),
+            );
+        }
+
+        let next = if objects.len() > limit {
+            objects.truncate(limit);
+            objects.last().map(|obj| obj.key.clone())
+        } else {
+            None
+        };
+
+        let mut listed = Vec::with_capacity(objects.len());
+        for obj in objects {
+            let info = match Record::load(&obj.key, storage).await? {
+                Some(Record {
+                    info: Some(info), ..
+                }) => Some(info),
+                // Images stored before records kept the info, their header is read instead.
+                _ => match Image::from_storage(obj.key.clone(), storage).await {
+                    Ok(img) => img.info(obj.modified).await.ok(),
+                    Err(ref err) if err.is_not_found() => continue,
+                    Err(err) => return Err(err),
+                },
+            };
+
+            listed.push(Listed {
+                key: obj.key,
+                size: obj.size,
+                modified: obj.modified,
+                info: info,
+            });
+        }
+
+        Ok((listed, next))
+    }
+
+    /// Reads the properties of the image from its header.
+    async fn info(self, created_at: SystemTime) -> Result<ImageInfo, Error> {
+        let format = self.format();
+        let data = self.data;
+        let (header, hash) =
+            tokio_executor::blocking::run(move || (libvips::header(&data), content_hash(&data)))
+                .await;
+        let header = header.or_internal_err().context("read header")?;
+
+        Ok(ImageInfo {
+            format: format,
+            width: header.width,
+            height: header.height,
+            hash: hash,
+            created_at: created_at,
+        })
+    }
+
+    /// Describes a variant of the stored image without reading it, rendering derivatives on the
+    /// first request. The content hash is taken from the record, only objects stored before it
+    /// was recorded are read and hashed.
+    pub(crate) async fn head(
+        key: &ImageKey,
+        storage: &dyn Storage,
+        variant: Variant<'_>,
+    ) -> Result<Head, Error> {
+        let path = match variant {
+            Variant::Thumbnail => key.clone(),
+            Variant::Original => key.derived(ORIGINALS_FOLDER, ""),
+            Variant::Derivative(opts) => Image::cache_derivative(key, storage, opts).await?,
+        };
+        let obj = storage.head(&path).await.context("find image")?;
+        let record = Record::load(key, storage).await?;
+
+        let format = match variant {
+            Variant::Thumbnail => record
+                .as_ref()
+                .and_then(|r| r.info.as_ref())
+                .and_then(|info| info.format),
+            Variant::Original => None,
+            Variant::Derivative(opts) => Some(opts.format),
+        };
+        let (hash, content_type) = match record.as_ref().and_then(|r| r.hash_of(&path)) {
+            Some(hash) => match format {
+                Some(format) => (hash, format.content_type()),
+                None => (hash, content_type(Image::sniff(&path, storage).await?)),
+            },
+            None => {
+                let img = Image::from_storage(path.clone(), storage).await?;
+                (content_hash(&img.data), img.content_type())
+            }
+        };
+
+        Ok(Head {
+            path: path,
+            size: obj.size,
+            // The storage time depends on how the backend copies objects, see `Head::modified`.
+            modified: record
+                .as_ref()
+                .and_then(|r| r.info.as_ref())
+                .map_or(obj.modified, |info| info.created_at),
+            content_type: content_type,
+            hash: hash,
+        })
+    }
+
+    /// Tells the format of a stored image from its first bytes.
+    async fn sniff(
+        path: &ImageKey,
+        storage: &dyn Storage,
+    ) -> Result<Option<libvips::InputFormat>, Error> {
+        let range = 0..libvips::InputFormat::MAGIC_LEN as u64;
+        let chunks = storage.read(path, range).await.context("read image")?;
+        let magic: Vec<u8> = chunks.try_concat().await.context("read image")?;
+        Ok(libvips::InputFormat::detect(&magic))
+    }
+
+    /// Reads the properties of the stored image from its header, along with the options the
+    /// image was made with, if they were recorded.
+    pub(crate) async fn meta(key: &ImageKey, storage: &dyn Storage) -> Result<Meta, Error> {
+        let img = Image::from_storage(key.clone(), storage).await?;
+        let format = img.format();
+        let size = img.data.len() as u64;
+        let data = img.data;
+        let header = tokio_executor::blocking::run(move || libvips::header(&data))
             .await
             .or_internal_err()
-            .context("read file")?;
+            .context("read header")?;
+
+        let options = Record::load(key, storage).await?.and_then(|record| {
+            record
+                .derivatives
+                .into_iter()
+                .find(|d| d.path == key.as_str())
+                .map(|d| d.options)
+        });
 
-        Ok(Image::new(filename, data))
+        Ok(Meta {
+            format: format,
+            size: size,
+            header: header,
+            options: options,
+        })
     }
 
-    pub(crate) async fn into_thumbnail(self) -> Result<Self, Error> {
+    pub(crate) async fn into_thumbnail(self, opts: &ThumbnailOptions) -> Result<Self, Error> {
+        let geometry = opts.geometry()?;
+        let encoding = opts.encoding()?;
         let data = self.data;
-        let res = tokio_executor::blocking::run(|| libvips::thumbnail(data))
+        let _permit = VIPS.acquire().await;
+        let res = tokio_executor::blocking::run(move || {
+            #[cfg(test)]
+            let _load = super::tests::VipsLoad::enter();
+            let header = libvips::header(&data)
+                .map_err(load_error)
+                .context("read header")?;
+            check_decode_limits(&header)?;
+            libvips::thumbnail(data, &geometry, &encoding).map_err(load_error)
+        })
+        .await?;
+
+        Ok(Image::new(self.key, res))
+    }
+
+    /// Detects the format of the image data by its signature.
+    pub(crate) fn format(&self) -> Option<libvips::Format> {
+        libvips::Format::detect(&self.data)
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        content_type(libvips::InputFormat::detect(&self.data))
+    }
+}
+
+fn content_type(format: Option<libvips::InputFormat>) -> &'static str {
+    format
+        .map(libvips::InputFormat::content_type)
+        .unwrap_or("application/octet-stream")
+}
+
+/// What is kept for an uploaded image: the untouched original and the derivatives made from it.
+/// Paths are storage keys.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Record {
+    pub(crate) original: String,
+    /// Shared data the original and the thumbnail were copied from, missing for images stored
+    /// before deduplication.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) blob: Option<BlobRef>,
+    /// Properties of the thumbnail, missing for images stored before they were kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) info: Option<ImageInfo>,
+    pub(crate) derivatives: Vec<Derivative>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ImageInfo {
+    pub(crate) format: Option<libvips::Format>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// Content hash of the thumbnail, see `content_hash`.
+    pub(crate) hash: String,
+    pub(crate) created_at: SystemTime,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Derivative {
+    pub(crate) path: String,
+    pub(crate) options: ThumbnailOptions,
+    /// Content hash of the derivative, missing for derivatives made before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hash: Option<String>,
+}
+
+impl Record {
+    pub(crate) async fn load(key: &ImageKey, storage: &dyn Storage) -> Result<Option<Self>, Error> {
+        let json = match storage.get(&key.derived(RECORDS_FOLDER, ".json")).await {
+            Err(ref err) if err.is_not_found() => return Ok(None),
+            res => res.context("read record")?,
+        };
+
+        let record = serde_json::from_slice(&json)
+            .or_internal_err()
+            .context("parse record")?;
+        Ok(Some(record))
+    }
+
+    /// Content hash of one of the objects kept for the image, if it was recorded.
+    fn hash_of(&self, path: &ImageKey) -> Option<String> {
+        if path.as_str() == self.original {
+            return self.blob.as_ref().map(|blob| blob.hash.clone());
+        }
+
+        self.derivatives
+            .iter()
+            .rev()
+            .find(|d| d.path == path.as_str())
+            .and_then(|d| d.hash.clone())
+    }
+
+    async fn save(&self, key: &ImageKey, storage: &dyn Storage) -> Result<(), Error> {
+        let json = serde_json::to_vec(self)
+            .or_internal_err()
+            .context("serialize record")?;
+
+        let path = key.derived(RECORDS_FOLDER, ".json");
+        storage.put(&path, json).await.context("save record")
+    }
+}
+
+/// Entry of `Image::list`.
+pub(crate) struct Listed {
+    pub(crate) key: ImageKey,
+    pub(crate) size: u64,
+    pub(crate) modified: SystemTime,
+    /// Missing if the header of an image stored without a record could not be read.
+    pub(crate) info: Option<ImageInfo>,
+}
+
+/// One of the objects kept for an image.
+#[derive(Clone, Copy)]
+pub(crate) enum Variant<'a> {
+    /// The thumbnail made on upload.
+    Thumbnail,
+    /// The untouched upload.
+    Original,
+    /// A derivative rendered on demand.
+    Derivative(&'a ThumbnailOptions),
+}
+
+/// Outcome of `Image::head`.
+pub(crate) struct Head {
+    /// Storage key of the variant.
+    pub(crate) path: ImageKey,
+    pub(crate) size: u64,
+    /// When the image was stored as recorded, the same for every backend and variant. The
+    /// storage time of images stored before records kept it.
+    pub(crate) modified: SystemTime,
+    pub(crate) content_type: &'static str,
+    pub(crate) hash: String,
+}
+
+/// Outcome of `Image::meta`.
+pub(crate) struct Meta {
+    pub(crate) format: Option<libvips::Format>,
+    pub(crate) size: u64,
+    pub(crate) header: libvips::Header,
+    pub(crate) options: Option<ThumbnailOptions>,
+}
+
+/// Outcome of `Image::store`. The store must be committed or rolled back, the image it replaced
+/// is kept until then.
+pub(crate) struct Stored {
+    pub(crate) key: ImageKey,
+    /// Whether the data of an earlier upload was reused.
+    pub(crate) deduplicated: bool,
+    /// Whether an existing image with the same name was replaced.
+    pub(crate) replaced: bool,
+    pub(crate) input_format: libvips::InputFormat,
+    blob: BlobRef,
+    backup: Option<Backup>,
+}
+
+impl Stored {
+    /// Makes the store final, dropping the image it replaced for good.
+    pub(crate) async fn commit(self, storage: &dyn Storage) -> Result<(), Error> {
+        let backup = match self.backup {
+            Some(backup) => backup,
+            None => return Ok(()),
+        };
+
+        if let Some(blob) = backup.record.as_ref().and_then(|r| r.blob.as_ref()) {
+            let _lock = RECORDS_LOCK.lock().await;
+            blob.release(storage)
+                .await
+                .context("release replaced blob")?;
+        }
+
+        delete_folder(storage, &backup.key(""))
             .await
-            .or_internal_err()?;
+            .context("delete backup")
+    }
 
-        Ok(Image::new(self.filename, res))
+    /// Undoes the store: the image it replaced is put back, or the new one deleted if it didn't
+    /// replace any. Derivatives cached for the replaced image are not restored, they're rendered
+    /// again on request.
+    pub(crate) async fn roll_back(self, storage: &dyn Storage) -> Result<(), Error> {
+        let _writer = writer(&self.key).acquire().await;
+        self.restore(storage).await
     }
 
-    pub(crate) async fn save(&self, path: &Path) -> Result<(), Error> {
-        let mut file = File::create(path.join(&self.filename))
+    /// `roll_back` for callers holding the writer of the image.
+    async fn restore(self, storage: &dyn Storage) -> Result<(), Error> {
+        let Stored {
+            key,
+            blob,
+            mut backup,
+            ..
+        } = self;
+        let original = key.derived(ORIGINALS_FOLDER, "");
+        match &backup {
+            Some(backup) => {
+                match storage.copy(&backup.key(ORIGINAL_BACKUP), &original).await {
+                    Err(ref err) if err.is_not_found() => delete_existing(storage, &original)
+                        .await
+                        .context("delete original")?,
+                    res => res.context("restore original")?,
+                }
+                storage
+                    .copy(&backup.key(THUMBNAIL_BACKUP), &key)
+                    .await
+                    .context("restore thumbnail")?;
+            }
+            None => delete_existing(storage, &original)
+                .await
+                .context("delete original")?,
+        }
+
+        {
+            // Under the lock, like in `put`, so no derivative of the rolled back image is cached
+            // after it, see `cache_derivative`.
+            let _lock = RECORDS_LOCK.lock().await;
+            delete_folder(storage, &key.derived(CACHE_FOLDER, "/"))
+                .await
+                .context("drop cached derivatives")?;
+            match backup.as_mut().and_then(|b| b.record.take()) {
+                Some(mut record) => {
+                    record.derivatives.retain(|d| d.path == key.as_str());
+                    record.save(&key, storage).await?;
+                }
+                None => delete_existing(storage, &key.derived(RECORDS_FOLDER, ".json"))
+                    .await
+                    .context("delete record")?,
+            }
+            blob.release(storage).await.context("release blob")?;
+        }
+
+        match backup {
+            Some(backup) => delete_folder(storage, &backup.key(""))
+                .await
+                .context("delete backup"),
+            None => delete_existing(storage, &key)
+                .await
+                .context("delete thumbnail"),
+        }
+    }
+}
+
+/// Copy of an image being replaced, kept under `.replaced/{id}/` until the store replacing it is
+/// committed or rolled back. The objects are copied, which shares them where the storage can.
+struct Backup {
+    id: String,
+    /// Missing for images stored before records were kept.
+    record: Option<Record>,
+}
+
+impl Backup {
+    /// Copies the image stored under the key, if there is one. Callers hold its writer.
+    async fn new(key: &ImageKey, storage: &dyn Storage) -> Result<Option<Self>, Error> {
+        match storage.head(key).await {
+            Err(ref err) if err.is_not_found() => return Ok(None),
+            res => res.context("find replaced image")?,
+        };
+
+        let mut backup = Backup {
+            id: key::ulid(),
+            record: None,
+        };
+        storage
+            .copy(key, &backup.key(THUMBNAIL_BACKUP))
             .await
+            .context("back up thumbnail")?;
+        match storage
+            .copy(
+                &key.derived(ORIGINALS_FOLDER, ""),
+                &backup.key(ORIGINAL_BACKUP),
+            )
+            .await
+        {
+            Err(ref err) if err.is_not_found() => {}
+            res => res.context("back up original")?,
+        }
+
+        let _lock = RECORDS_LOCK.lock().await;
+        backup.record = Record::load(key, storage).await?;
+        Ok(Some(backup))
+    }
+
+    fn key(&self, name: &str) -> ImageKey {
+        ImageKey::parse(&format!("{}/{}/{}", REPLACED_FOLDER, self.id, name))
+            .expect("valid backup key")
+    }
+}
+
+/// Reference to the blobs of an image: the folder `{hash}` of the SHA-256 of the upload holding
+/// the original and a thumbnail per variant. Blobs are copied to the image names and reference
+/// counted in the `BlobRefs` of the folder, so they are deleted with the last image using them.
+/// Only backends sharing data on copy (the file system) save storage this way, the others only
+/// save rendering the thumbnail again.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct BlobRef {
+    pub(crate) hash: String,
+    pub(crate) variant: String,
+}
+
+/// Number of records referencing each blob of a folder.
+#[derive(Default, Deserialize, Serialize)]
+struct BlobRefs(BTreeMap<String, u32>);
+
+impl BlobRef {
+    fn key(&self, name: &str) -> ImageKey {
+        ImageKey::parse(&format!("{}/{}/{}", BLOBS_FOLDER, self.hash, name))
+            .expect("valid blob key")
+    }
+
+    /// Takes a reference to the original and the variant. If the variant doesn't exist yet, it's
+    /// stored from `data` (the original and the thumbnail), or nothing is done if `data` is
+    /// missing. Returns whether a reference was taken. Callers hold `RECORDS_LOCK`.
+    async fn acquire(
+        &self,
+        storage: &dyn Storage,
+        data: Option<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<bool, Error> {
+        let mut refs = self.load_refs(storage).await?;
+        if !refs.0.contains_key(&self.variant) {
+            let (original, thumbnail) = match data {
+                Some(data) => data,
+                None => return Ok(false),
+            };
+
+            if !refs.0.contains_key(ORIGINAL_BLOB) {
+                storage
+                    .put(&self.key(ORIGINAL_BLOB), original)
+                    .await
+                    .context("save original blob")?;
+            }
+            storage
+                .put(&self.key(&self.variant), thumbnail)
+                .await
+                .context("save thumbnail blob")?;
+        }
+
+        for name in &[ORIGINAL_BLOB, self.variant.as_str()] {
+            *refs.0.entry(name.to_string()).or_default() += 1;
+        }
+        self.save_refs(storage, &refs).await?;
+        Ok(true)
+    }
+
+    /// Drops the reference taken for an image that couldn't be stored. A failure is only
+    /// logged, the one that made the store fail is what matters.
+    async fn abandon(&self, storage: &dyn Storage) {
+        let _lock = RECORDS_LOCK.lock().await;
+        if let Err(err) = self.release(storage).await {
+            error!("release blob {}: {}", self.hash, err);
+        }
+    }
+
+    /// Drops a reference taken by `acquire`, deleting the blobs nobody references anymore.
+    /// Callers hold `RECORDS_LOCK`.
+    pub(crate) async fn release(&self, storage: &dyn Storage) -> Result<(), Error> {
+        let mut refs = self.load_refs(storage).await?;
+        for name in &[ORIGINAL_BLOB, self.variant.as_str()] {
+            let count = refs.0.entry(name.to_string()).or_default();
+            *count = count.saturating_sub(1);
+            if *count > 0 {
+                continue;
+            }
+
+            refs.0.remove(*name);
+            delete_existing(storage, &self.key(name))
+                .await
+                .context("delete blob")?;
+        }
+
+        if refs.0.is_empty() {
+            delete_existing(storage, &self.key(BLOB_REFS))
+                .await
+                .context("delete blob refs")
+        } else {
+            self.save_refs(storage, &refs).await
+        }
+    }
+
+    async fn load_refs(&self, storage: &dyn Storage) -> Result<BlobRefs, Error> {
+        let json = match storage.get(&self.key(BLOB_REFS)).await {
+            Err(ref err) if err.is_not_found() => return Ok(BlobRefs::default()),
+            res => res.context("read blob refs")?,
+        };
+
+        serde_json::from_slice(&json)
             .or_internal_err()
-            .context("create file")?;
+            .context("parse blob refs")
+    }
 
-        file.write_all(&self.data)
-            .await
+    async fn save_refs(&self, storage: &dyn Storage, refs: &BlobRefs) -> Result<(), Error> {
+        let json = serde_json::to_vec(refs)
             .or_internal_err()
-            .context("write file")?;
+            .context("serialize blob refs")?;
+        storage
+            .put(&self.key(BLOB_REFS), json)
+            .await
+            .context("save blob refs")
+    }
+}
 
+/// Deletes every object directly inside the storage folder, given as a prefix ending with `/`.
+async fn delete_folder(storage: &dyn Storage, folder: &ImageKey) -> Result<(), Error> {
+    loop {
+        let objects = storage
+            .list(folder, None, LIST_PAGE_SIZE)
+            .await
+            .context("list folder")?;
+        if objects.is_empty() {
+            return Ok(());
+        }
+
+        for obj in objects {
+            delete_existing(storage, &obj.key)
+                .await
+                .context("delete file")?;
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of the data.
+fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Deletes the object, if it's still there.
+async fn delete_existing(storage: &dyn Storage, key: &ImageKey) -> Result<(), storage::Error> {
+    match storage.delete(key).await {
+        Err(ref err) if err.is_not_found() => Ok(()),
+        res => res,
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub(crate) struct ThumbnailOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) height: Option<u32>,
+    #[serde(default)]
+    pub(crate) crop: libvips::Crop,
+    #[serde(default)]
+    pub(crate) size: libvips::Size,
+    #[serde(default)]
+    pub(crate) format: libvips::Format,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) quality: Option<u8>,
+    #[serde(default)]
+    pub(crate) lossless: bool,
+    #[serde(default, alias = "progressive")]
+    pub(crate) interlace: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) effort: Option<u8>,
+}
+
+impl ThumbnailOptions {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        self.geometry()?;
+        self.encoding()?;
         Ok(())
     }
+
+    /// Checks options a client sent as query parameters against the resizing policy.
+    pub(crate) fn check_ad_hoc(&self) -> Result<(), Error> {
+        let policy = &CONFIG.resizing;
+        if !policy.ad_hoc {
+            return Err(validation_error(
+                "query",
+                "resizing by query parameters is disabled, use a preset",
+            ));
+        }
+
+        for &(arg, value) in &[("w", self.width), ("h", self.height)] {
+            match value {
+                Some(v) if v > policy.max_size => {
+                    let details = format!("must be at most {}", policy.max_size);
+                    return Err(validation_error(arg, &details));
+                }
+                Some(v) if !policy.sizes.is_empty() && !policy.sizes.contains(&v) => {
+                    let sizes: Vec<_> = policy.sizes.iter().map(u32::to_string).collect();
+                    let details = format!("must be one of {}", sizes.join(", "));
+                    return Err(validation_error(arg, &details));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Canonical file name of a derivative rendered with these options.
+    fn variant_name(&self) -> String {
+        let dimension = |v: Option<u32>| v.map(|v| v.to_string()).unwrap_or_default();
+        let number = |v: Option<u8>| v.map(|v| v.to_string()).unwrap_or_default();
+        format!(
+            "{}x{}-{:?}-{:?}-q{}-e{}-l{}-i{}.{:?}",
+            dimension(self.width),
+            dimension(self.height),
+            self.crop,
+            self.size,
+            number(self.quality),
+            number(self.effort),
+            self.lossless as u8,
+            self.interlace as u8,
+            self.format,
+        )
+        .to_lowercase()
+    }
+
+    fn geometry(&self) -> Result<libvips::Geometry, Error> {
+        check_dimension("width", self.width)?;
+        check_dimension("height", self.height)?;
+        Ok(libvips::Geometry {
+            width: self.width,
+            height: self.height,
+            crop: self.crop,
+            size: self.size,
+        })
+    }
+
+    fn encoding(&self) -> Result<libvips::Encoding, Error> {
+        if let Some(q) = self.quality {
+            if q == 0 || q > 100 {
+                return Err(validation_error("quality", "must be between 1 and 100"));
+            }
+        }
+
+        if self.lossless && !self.format.supports_lossless() {
+            return Err(validation_error(
+                "lossless",
+                "supported by webp and avif only",
+            ));
+        }
+
+        if let Some(effort) = self.effort {
+            match self.format.effort_range() {
+                Some((min, max)) if effort < min || effort > max => {
+                    let details = format!("must be between {} and {}", min, max);
+                    return Err(validation_error("effort", &details));
+                }
+                None => return Err(validation_error("effort", "not supported by jpeg")),
+                _ => {}
+            }
+        }
+
+        Ok(libvips::Encoding {
+            format: self.format,
+            quality: self.quality,
+            lossless: self.lossless,
+            interlace: self.interlace,
+            effort: self.effort,
+        })
+    }
+}
+
+fn validation_error(arg: &str, details: &str) -> Error {
+    Error::invalid_argument(arg, details, ErrorCause::Validation)
+}
+
+/// Tells the format of uploaded data, rejecting formats that are not allowed.
+fn check_input_format(data: &[u8]) -> Result<libvips::InputFormat, Error> {
+    match libvips::InputFormat::detect(data) {
+        Some(format) if CONFIG.input_formats.contains(&format) => Ok(format),
+        format => Err(Error::new(
+            ErrorKind::UnsupportedFormat,
+            ErrorCause::UnsupportedFormat(format),
+        )),
+    }
+}
+
+/// Blames images libvips could not read on the request, other failures are internal.
+fn load_error(err: libvips::Error) -> Error {
+    if err.is_load() {
+        Error::invalid_argument("data", "not a readable image", err)
+    } else {
+        Error::internal(err)
+    }
+}
+
+/// Rejects images whose decoded pixels would take too much memory.
+fn check_decode_limits(header: &libvips::Header) -> Result<(), Error> {
+    let limits = &CONFIG.decode;
+    if header.width > limits.max_width || header.height > limits.max_height {
+        return Err(validation_error(
+            "data",
+            &format!(
+                "image is {}x{} pixels, at most {}x{} are allowed",
+                header.width, header.height, limits.max_width, limits.max_height
+            ),
+        ));
+    }
+
+    let pixels = u64::from(header.width) * u64::from(header.height);
+    if pixels > limits.max_pixels {
+        return Err(validation_error(
+            "data",
+            &format!(
+                "image has {} pixels, at most {} are allowed",
+                pixels, limits.max_pixels
+            ),
+        ));
+    }
+
+    if header.pages > limits.max_pages {
+        return Err(validation_error(
+            "data",
+            &format!(
+                "image has {} pages, at most {} are allowed",
+                header.pages, limits.max_pages
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_dimension(arg: &str, value: Option<u32>) -> Result<(), Error> {
+    match value {
+        Some(v) if v == 0 || v > MAX_DIMENSION => Err(validation_error(
+            arg,
+            &format!("must be between 1 and {}", MAX_DIMENSION),
+        )),
+        _ => Ok(()),
+    }
 }
 
 #[derive(Debug)]
@@ -119,12 +1241,11 @@ impl Error {
         self
     }
 
-    fn map_io(err: std::io::Error, io_kind: std::io::ErrorKind, kind: ErrorKind) -> Self {
-        if err.kind() == io_kind {
-            return Error::new(kind, err);
-        };
-
-        Error::new(ErrorKind::Internal, err)
+    pub(crate) fn is_not_found(&self) -> bool {
+        match self.kind {
+            ErrorKind::NotFound => true,
+            _ => false,
+        }
     }
 }
 
@@ -147,8 +1268,15 @@ impl InvalidArgumentError {
 pub(crate) enum ErrorCause {
     IO(std::io::Error),
     Reqwest(reqwest::Error),
+    Fetch(fetch::Error),
     Base64Decode(base64::DecodeError),
     Libvips(libvips::Error),
+    Json(serde_json::Error),
+    Storage(storage::Error),
+    Key(KeyError),
+    Conflict(ImageKey),
+    UnsupportedFormat(Option<libvips::InputFormat>),
+    Validation,
 }
 
 impl std::fmt::Display for ErrorCause {
@@ -156,8 +1284,18 @@ impl std::fmt::Display for ErrorCause {
         match self {
             ErrorCause::IO(err) => write!(f, "io: {}", err),
             ErrorCause::Reqwest(err) => write!(f, "reqwest: {}", err),
+            ErrorCause::Fetch(err) => write!(f, "fetch: {}", err),
             ErrorCause::Base64Decode(err) => write!(f, "base64: {}", err),
             ErrorCause::Libvips(err) => write!(f, "libvips: {}", err),
+            ErrorCause::Json(err) => write!(f, "json: {}", err),
+            ErrorCause::Storage(err) => write!(f, "storage: {}", err),
+            ErrorCause::Key(err) => write!(f, "key: {}", err),
+            ErrorCause::Conflict(key) => write!(f, "{} already exists", key),
+            ErrorCause::UnsupportedFormat(Some(format)) => {
+                write!(f, "{} images are not accepted", format.name())
+            }
+            ErrorCause::UnsupportedFormat(None) => write!(f, "unknown image format"),
+            ErrorCause::Validation => write!(f, "validation failed"),
         }
     }
 }
@@ -166,6 +1304,8 @@ impl std::fmt::Display for ErrorCause {
 pub(crate) enum ErrorKind {
     InvalidArgument(InvalidArgumentError),
     NotFound,
+    Conflict,
+    UnsupportedFormat,
     Internal,
 }
 
@@ -193,6 +1333,35 @@ impl From<libvips::Error> for ErrorCause {
     }
 }
 
+impl From<serde_json::Error> for ErrorCause {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl From<storage::Error> for ErrorCause {
+    fn from(err: storage::Error) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<KeyError> for ErrorCause {
+    fn from(err: KeyError) -> Self {
+        Self::Key(err)
+    }
+}
+
+impl From<storage::Error> for Error {
+    fn from(err: storage::Error) -> Self {
+        let kind = match err {
+            storage::Error::NotFound => ErrorKind::NotFound,
+            _ => ErrorKind::Internal,
+        };
+
+        Error::new(kind, err)
+    }
+}
+
 trait WrapError<T> {
     fn or_invalid_argument(self, arg: &str, details: &str) -> Result<T, Error>;
     fn or_internal_err(self) -> Result<T, Error>;
@@ -217,3 +1386,9 @@ impl<T> ErrorContext<T> for Result<T, Error> {
         self.map_err(|err| err.context(ctx))
     }
 }
+
+impl<T> ErrorContext<T> for Result<T, storage::Error> {
+    fn context(self, ctx: &str) -> Result<T, Error> {
+        self.map_err(|err| Error::from(err).context(ctx))
+    }
+}
@@ -0,0 +1,119 @@
+mod fs;
+mod memory;
+mod s3;
+
+use super::config::StorageConfig;
+use super::key::ImageKey;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use std::ops::Range;
+use std::time::SystemTime;
+
+pub(crate) use fs::FsStorage;
+pub(crate) use memory::MemoryStorage;
+pub(crate) use s3::S3Storage;
+
+/// Size of the chunks files and in-memory objects are read in.
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Data of an object in chunks, in order.
+pub(crate) type Chunks = BoxStream<'static, Result<Vec<u8>, Error>>;
+
+/// Flat key-value store of blobs. Keys are `/`-separated paths relative to the storage root.
+pub(crate) trait Storage: Send + Sync {
+    fn put<'a>(&'a self, key: &'a ImageKey, data: Vec<u8>) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn get<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Vec<u8>, Error>>;
+
+    fn head<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Object, Error>>;
+
+    /// Streams the bytes in `range` of the object, so it's never held in memory as a whole.
+    /// The stream ends early if the object got shorter than the range.
+    fn read<'a>(
+        &'a self,
+        key: &'a ImageKey,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Chunks, Error>>;
+
+    /// Makes `to` hold the data of `from`, sharing it instead of copying where the backend can.
+    /// Fails with `Error::NotFound` if `from` doesn't exist.
+    fn copy<'a>(&'a self, from: &'a ImageKey, to: &'a ImageKey)
+        -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Fails with `Error::NotFound` if there is nothing to delete.
+    fn delete<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Lists up to `limit` objects whose keys start with `prefix` and that lie directly in the
+    /// folder of `prefix` (no further `/` after it), in lexicographic order of keys, starting
+    /// after `start_after`.
+    fn list<'a>(
+        &'a self,
+        prefix: &'a ImageKey,
+        start_after: Option<&'a ImageKey>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Object>, Error>>;
+}
+
+pub(crate) fn new(cfg: &StorageConfig) -> Box<dyn Storage> {
+    match cfg {
+        StorageConfig::Fs { folder } => Box::new(FsStorage::new(folder.into())),
+        StorageConfig::Memory => Box::new(MemoryStorage::new()),
+        StorageConfig::S3(cfg) => Box::new(S3Storage::new(cfg)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Object {
+    pub(crate) key: ImageKey,
+    pub(crate) size: u64,
+    pub(crate) modified: SystemTime,
+}
+
+/// Whether `key` is listed for `prefix`, see `Storage::list`.
+fn is_listed(key: &str, prefix: &ImageKey, start_after: Option<&ImageKey>) -> bool {
+    let prefix = prefix.as_str();
+    key.starts_with(prefix)
+        && !key[prefix.len()..].contains('/')
+        && start_after.map(|s| key > s.as_str()).unwrap_or(true)
+}
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    NotFound,
+    IO(std::io::Error),
+    Reqwest(reqwest::Error),
+    S3(String),
+}
+
+impl Error {
+    pub(crate) fn is_not_found(&self) -> bool {
+        match self {
+            Error::NotFound => true,
+            _ => false,
+        }
+    }
+
+    fn from_io(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => Error::NotFound,
+            _ => Error::IO(err),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "not found"),
+            Error::IO(err) => write!(f, "io: {}", err),
+            Error::Reqwest(err) => write!(f, "reqwest: {}", err),
+            Error::S3(err) => write!(f, "s3: {}", err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Reqwest(err)
+    }
+}
@@ -0,0 +1,342 @@
+use super::super::key::ImageKey;
+use super::{is_listed, Chunks, Error, Object, Storage, CHUNK_SIZE};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeSet, HashMap};
+use std::io::SeekFrom;
+use std::ops::{Bound, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::{fs::File, prelude::*};
+
+/// Folder (relative to the root) for files being written.
+const TMP_FOLDER: &str = ".tmp";
+
+/// Times a rename is tried while concurrent deletes remove the folders it creates.
+const RENAME_ATTEMPTS: usize = 5;
+
+static TMP_FILE_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Sorted file names per folder (with its trailing `/`, empty for the root).
+type Index = HashMap<String, BTreeSet<String>>;
+
+/// Keeps objects as files under a root folder.
+///
+/// Directories aren't sorted, so the first listing of a folder reads all its entries (O(n) in
+/// the folder size) into a sorted index, kept in memory and up to date by the writes of this
+/// storage. Later listings only cost their page. Files changed by other processes while running
+/// aren't seen by the listings.
+pub(crate) struct FsStorage {
+    root: PathBuf,
+    index: Arc<Mutex<Index>>,
+}
+
+impl FsStorage {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            index: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn path(&self, key: &ImageKey) -> PathBuf {
+        self.root.join(key.as_str())
+    }
+
+    /// Writes the file through a temporary one, so readers never see it partially written.
+    async fn write(&self, key: &ImageKey, data: &[u8]) -> Result<(), std::io::Error> {
+        let tmp = self.tmp_path().await?;
+        let written = async {
+            let mut file = File::create(&tmp).await?;
+            file.write_all(data).await
+        }
+        .await;
+        self.commit(&tmp, written, key).await
+    }
+
+    /// Hard links the file, so both keys share the data on disk. Links share the modification
+    /// time as well, it's set to now like the other backends have it on a copy.
+    async fn link(&self, from: &ImageKey, to: &ImageKey) -> Result<(), std::io::Error> {
+        let tmp = self.tmp_path().await?;
+        let linked = async {
+            tokio::fs::hard_link(self.path(from), &tmp).await?;
+            let linked = tmp.clone();
+            tokio_executor::blocking::run(move || {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&linked)?
+                    .set_modified(SystemTime::now())
+            })
+            .await
+        }
+        .await;
+        self.commit(&tmp, linked, to).await
+    }
+
+    /// Path of a new temporary file.
+    async fn tmp_path(&self) -> Result<PathBuf, std::io::Error> {
+        let tmp_folder = self.root.join(TMP_FOLDER);
+        tokio::fs::create_dir_all(&tmp_folder).await?;
+        let seq = TMP_FILE_SEQ.fetch_add(1, Ordering::Relaxed);
+        Ok(tmp_folder.join(format!("{}.{}", std::process::id(), seq)))
+    }
+
+    /// Moves a temporary file to its key once it's `written`, the file is removed if either fails.
+    async fn commit(
+        &self,
+        tmp: &Path,
+        written: Result<(), std::io::Error>,
+        key: &ImageKey,
+    ) -> Result<(), std::io::Error> {
+        let res = match written {
+            Ok(()) => self.rename(tmp, key).await,
+            Err(err) => Err(err),
+        };
+        match res {
+            Ok(()) => self.update_index(key, true),
+            Err(_) => {
+                let _ = tokio::fs::remove_file(tmp).await;
+            }
+        }
+        res
+    }
+
+    /// Adds `key` to or removes it from the index, if its folder was listed yet.
+    fn update_index(&self, key: &ImageKey, exists: bool) {
+        let (folder, name) = split(key.as_str());
+        let mut index = self.index.lock().expect("lock index");
+        if let Some(names) = index.get_mut(folder) {
+            if exists {
+                names.insert(name.to_string());
+            } else {
+                names.remove(name);
+            }
+        }
+    }
+
+    /// Renames the file to the path of `key`, creating the folders on the way. Deletes remove
+    /// the folders they leave empty, so one may vanish again before the rename: that's retried.
+    async fn rename(&self, tmp: &Path, key: &ImageKey) -> Result<(), std::io::Error> {
+        let path = self.path(key);
+        let mut attempt = 1;
+        loop {
+            let res = match path.parent() {
+                Some(parent) => tokio::fs::create_dir_all(parent).await,
+                None => Ok(()),
+            };
+            let res = match res {
+                Ok(()) => tokio::fs::rename(tmp, &path).await,
+                Err(err) => Err(err),
+            };
+            match res {
+                Err(ref err)
+                    if err.kind() == std::io::ErrorKind::NotFound && attempt < RENAME_ATTEMPTS =>
+                {
+                    attempt += 1
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Removes the folders left empty after deleting `key`, up to the root.
+    async fn remove_empty_parents(&self, key: &ImageKey) {
+        let mut path = Path::new(key.as_str());
+        while let Some(parent) = path.parent() {
+            if parent.as_os_str().is_empty()
+                || tokio::fs::remove_dir(self.root.join(parent)).await.is_err()
+            {
+                break;
+            }
+            path = parent;
+        }
+    }
+}
+
+impl Storage for FsStorage {
+    fn put<'a>(&'a self, key: &'a ImageKey, data: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        async move { self.write(key, &data).await.map_err(Error::IO) }.boxed()
+    }
+
+    fn get<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+        async move {
+            tokio::fs::read(self.path(key))
+                .await
+                .map_err(Error::from_io)
+        }
+        .boxed()
+    }
+
+    fn head<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Object, Error>> {
+        async move {
+            let meta = tokio::fs::metadata(self.path(key))
+                .await
+                .map_err(Error::from_io)?;
+            if !meta.is_file() {
+                return Err(Error::NotFound);
+            }
+
+            Ok(Object {
+                key: key.clone(),
+                size: meta.len(),
+                modified: meta.modified().map_err(Error::IO)?,
+            })
+        }
+        .boxed()
+    }
+
+    fn read<'a>(
+        &'a self,
+        key: &'a ImageKey,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Chunks, Error>> {
+        async move {
+            let mut file = File::open(self.path(key)).await.map_err(Error::from_io)?;
+            file.seek(SeekFrom::Start(range.start))
+                .await
+                .map_err(Error::IO)?;
+
+            let remaining = range.end.saturating_sub(range.start);
+            let chunks = stream::unfold((file, remaining), |(mut file, remaining)| async move {
+                if remaining == 0 {
+                    return None;
+                }
+
+                let mut buf = vec![0; remaining.min(CHUNK_SIZE as u64) as usize];
+                match file.read(&mut buf).await {
+                    Ok(0) => None,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((Ok(buf), (file, remaining - n as u64)))
+                    }
+                    Err(err) => Some((Err(Error::IO(err)), (file, 0))),
+                }
+            });
+            Ok(chunks.boxed())
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(
+        &'a self,
+        from: &'a ImageKey,
+        to: &'a ImageKey,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move { self.link(from, to).await.map_err(Error::from_io) }.boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            tokio::fs::remove_file(self.path(key))
+                .await
+                .map_err(Error::from_io)?;
+            self.update_index(key, false);
+            self.remove_empty_parents(key).await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list<'a>(
+        &'a self,
+        prefix: &'a ImageKey,
+        start_after: Option<&'a ImageKey>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Object>, Error>> {
+        let (folder, name_prefix) = split(prefix.as_str());
+        let (folder, name_prefix) = (folder.to_string(), name_prefix.to_string());
+        let root = self.root.clone();
+        let index = self.index.clone();
+        let prefix = prefix.clone();
+        let start_after = start_after.cloned();
+
+        // Files are only stat'ed once they made it into the page, the index is locked meanwhile
+        // to take the next names only.
+        tokio_executor::blocking::run(move || {
+            let mut objects = Vec::new();
+            let mut last = start_after.as_ref().map(|key| key.to_string());
+            while objects.len() < limit {
+                let keys = {
+                    // Held while reading the folder, so writes committed meanwhile aren't missed.
+                    let mut index = index.lock().expect("lock index");
+                    if !index.contains_key(&folder) {
+                        let names = read_names(&root.join(&folder)).map_err(Error::IO)?;
+                        index.insert(folder.clone(), names);
+                    }
+
+                    let start = match last {
+                        Some(ref key)
+                            if key.starts_with(&folder) && key[folder.len()..] > *name_prefix =>
+                        {
+                            Bound::Excluded(&key[folder.len()..])
+                        }
+                        _ => Bound::Included(name_prefix.as_str()),
+                    };
+                    index[&folder]
+                        .range::<str, _>((start, Bound::Unbounded))
+                        .take_while(|name| name.starts_with(&name_prefix))
+                        .map(|name| format!("{}{}", folder, name))
+                        .filter(|key| is_listed(key, &prefix, start_after.as_ref()))
+                        .take(limit - objects.len())
+                        .collect::<Vec<_>>()
+                };
+                if keys.is_empty() {
+                    break;
+                }
+
+                last = keys.last().cloned();
+                for key in keys {
+                    // Skip files which could not have been stored under a valid key.
+                    let key = match ImageKey::parse(&key) {
+                        Ok(key) => key,
+                        Err(_) => continue,
+                    };
+                    let meta = match std::fs::metadata(root.join(key.as_str())) {
+                        // Deleted since the names were taken.
+                        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                        res => res.map_err(Error::IO)?,
+                    };
+                    if !meta.is_file() {
+                        continue;
+                    }
+
+                    objects.push(Object {
+                        key: key,
+                        size: meta.len(),
+                        modified: meta.modified().map_err(Error::IO)?,
+                    });
+                }
+            }
+
+            Ok(objects)
+        })
+        .boxed()
+    }
+}
+
+/// Splits a key into its folder (with the trailing `/`) and the name in it.
+fn split(key: &str) -> (&str, &str) {
+    match key.rfind('/') {
+        Some(i) => key.split_at(i + 1),
+        None => ("", key),
+    }
+}
+
+/// Names of the files in `folder`, none if it doesn't exist.
+fn read_names(folder: &Path) -> Result<BTreeSet<String>, std::io::Error> {
+    let entries = match std::fs::read_dir(folder) {
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        res => res?,
+    };
+
+    let mut names = BTreeSet::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.insert(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    Ok(names)
+}
@@ -0,0 +1,117 @@
+use super::super::key::ImageKey;
+use super::{is_listed, Chunks, Error, Object, Storage, CHUNK_SIZE};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// Keeps everything in memory, meant for tests and throwaway setups.
+/// Copies and readers share the data of an object.
+pub(crate) struct MemoryStorage {
+    objects: RwLock<BTreeMap<ImageKey, (Arc<Vec<u8>>, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    pub(crate) fn new() -> Self {
+        Self {
+            objects: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put<'a>(&'a self, key: &'a ImageKey, data: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        let mut objects = self.objects.write().expect("lock objects");
+        objects.insert(key.clone(), (Arc::new(data), SystemTime::now()));
+        futures::future::ok(()).boxed()
+    }
+
+    fn get<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+        let objects = self.objects.read().expect("lock objects");
+        let res = objects
+            .get(key)
+            .map(|(data, _)| data.as_ref().clone())
+            .ok_or(Error::NotFound);
+        futures::future::ready(res).boxed()
+    }
+
+    fn head<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Object, Error>> {
+        let objects = self.objects.read().expect("lock objects");
+        let res = objects
+            .get(key)
+            .map(|(data, modified)| Object {
+                key: key.clone(),
+                size: data.len() as u64,
+                modified: *modified,
+            })
+            .ok_or(Error::NotFound);
+        futures::future::ready(res).boxed()
+    }
+
+    fn read<'a>(
+        &'a self,
+        key: &'a ImageKey,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Chunks, Error>> {
+        let objects = self.objects.read().expect("lock objects");
+        let res = objects
+            .get(key)
+            .cloned()
+            .ok_or(Error::NotFound)
+            .map(|(data, _)| {
+                let end = (range.end as usize).min(data.len());
+                let start = (range.start as usize).min(end);
+                let chunks = (start..end)
+                    .step_by(CHUNK_SIZE)
+                    .map(move |i| Ok(data[i..(i + CHUNK_SIZE).min(end)].to_vec()));
+                stream::iter(chunks).boxed()
+            });
+        futures::future::ready(res).boxed()
+    }
+
+    fn copy<'a>(
+        &'a self,
+        from: &'a ImageKey,
+        to: &'a ImageKey,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut objects = self.objects.write().expect("lock objects");
+        let res = match objects.get(from) {
+            Some((data, _)) => {
+                let data = data.clone();
+                objects.insert(to.clone(), (data, SystemTime::now()));
+                Ok(())
+            }
+            None => Err(Error::NotFound),
+        };
+        futures::future::ready(res).boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<(), Error>> {
+        let mut objects = self.objects.write().expect("lock objects");
+        let res = objects.remove(key).map(|_| ()).ok_or(Error::NotFound);
+        futures::future::ready(res).boxed()
+    }
+
+    fn list<'a>(
+        &'a self,
+        prefix: &'a ImageKey,
+        start_after: Option<&'a ImageKey>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Object>, Error>> {
+        let objects = self.objects.read().expect("lock objects");
+        let res = objects
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.as_str().starts_with(prefix.as_str()))
+            .filter(|(key, _)| is_listed(key.as_str(), prefix, start_after))
+            .take(limit)
+            .map(|(key, (data, modified))| Object {
+                key: key.clone(),
+                size: data.len() as u64,
+                modified: *modified,
+            })
+            .collect();
+        futures::future::ok(res).boxed()
+    }
+}
@@ -0,0 +1,346 @@
+use super::super::config::S3Config;
+use super::super::key::ImageKey;
+use super::{is_listed, Chunks, Error, Object, Storage};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use reqwest::header::{HeaderName, AUTHORIZATION, CONTENT_LENGTH, LAST_MODIFIED};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+use std::time::SystemTime;
+
+lazy_static! {
+    static ref CONTENTS: Regex = Regex::new(r"(?s)<Contents>(.*?)</Contents>").expect("regexp");
+    static ref KEY: Regex = Regex::new(r"<Key>(.*?)</Key>").expect("regexp");
+    static ref SIZE: Regex = Regex::new(r"<Size>(\d+)</Size>").expect("regexp");
+    static ref LAST_MODIFIED_TAG: Regex =
+        Regex::new(r"<LastModified>(.*?)</LastModified>").expect("regexp");
+}
+
+/// Keeps objects in a bucket of an S3 compatible service, addressed path-style
+/// (`{endpoint}/{bucket}/{key}`) so it works with MinIO out of the box.
+pub(crate) struct S3Storage {
+    client: reqwest::Client,
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Storage {
+    pub(crate) fn new(cfg: &S3Config) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: cfg.endpoint.trim_end_matches('/').to_string(),
+            bucket: cfg.bucket.clone(),
+            region: cfg.region.clone(),
+            access_key: cfg.access_key.clone(),
+            secret_key: cfg.secret_key.clone(),
+        }
+    }
+
+    /// Sends an AWS Signature Version 4 signed request, failing on unsuccessful statuses.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, Error> {
+        let path = match key {
+            "" => format!("/{}", self.bucket),
+            _ => format!("/{}/{}", self.bucket, uri_encode(key, false)),
+        };
+
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let sep = if query.is_empty() { "" } else { "?" };
+        let url = Url::parse(&format!("{}{}{}{}", self.endpoint, path, sep, query))
+            .map_err(|e| Error::S3(format!("invalid url: {}", e)))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let amz_date = amz_date(SystemTime::now());
+        let date = &amz_date[..8];
+
+        // Every extra header is signed along with the mandatory ones.
+        let mut signed = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        signed.extend_from_slice(headers);
+        signed.sort();
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v))
+            .collect();
+        let signed_headers = signed.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method.as_str(),
+            path,
+            query,
+            canonical_headers,
+            signed_headers,
+            payload_hash,
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+
+        let signing_key = [date, self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_key).into_bytes(),
+                |key, part| hmac(&key, part.as_bytes()),
+            );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            signed_headers,
+            hex::encode(hmac(&signing_key, string_to_sign.as_bytes())),
+        );
+
+        let mut req = self
+            .client
+            .request(method, url)
+            .header("x-amz-date", amz_date.as_str())
+            .header("x-amz-content-sha256", payload_hash.as_str())
+            .header(AUTHORIZATION, authorization.as_str());
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        let resp = req.body(body).send().await?;
+
+        match resp.status() {
+            s if s.is_success() => Ok(resp),
+            StatusCode::NOT_FOUND => Err(Error::NotFound),
+            s => {
+                let details = resp.text().await.unwrap_or_default();
+                Err(Error::S3(format!("{}: {}", s, details)))
+            }
+        }
+    }
+}
+
+impl Storage for S3Storage {
+    fn put<'a>(&'a self, key: &'a ImageKey, data: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.send(Method::PUT, key.as_str(), &[], &[], data).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+        async move {
+            let resp = self
+                .send(Method::GET, key.as_str(), &[], &[], Vec::new())
+                .await?;
+            Ok(resp.bytes().await?.to_vec())
+        }
+        .boxed()
+    }
+
+    fn head<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<Object, Error>> {
+        async move {
+            let resp = self
+                .send(Method::HEAD, key.as_str(), &[], &[], Vec::new())
+                .await?;
+            let header = |name: HeaderName| {
+                resp.headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or_else(|| Error::S3(format!("missing {} header", name)))
+            };
+
+            Ok(Object {
+                key: key.clone(),
+                size: header(CONTENT_LENGTH)?
+                    .parse()
+                    .map_err(|_| Error::S3("invalid content-length".to_string()))?,
+                modified: httpdate::parse_http_date(header(LAST_MODIFIED)?)
+                    .map_err(|_| Error::S3("invalid last-modified".to_string()))?,
+            })
+        }
+        .boxed()
+    }
+
+    fn read<'a>(
+        &'a self,
+        key: &'a ImageKey,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Chunks, Error>> {
+        async move {
+            // An empty range can't be expressed in a Range header.
+            if range.end <= range.start {
+                self.head(key).await?;
+                return Ok(stream::empty().boxed());
+            }
+
+            let range = format!("bytes={}-{}", range.start, range.end - 1);
+            let resp = self
+                .send(
+                    Method::GET,
+                    key.as_str(),
+                    &[],
+                    &[("range", range.as_str())],
+                    Vec::new(),
+                )
+                .await?;
+            let chunks = stream::unfold(Some(resp), |resp| async move {
+                let mut resp = resp?;
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), Some(resp))),
+                    Ok(None) => None,
+                    Err(err) => Some((Err(Error::Reqwest(err)), None)),
+                }
+            });
+            Ok(chunks.boxed())
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(
+        &'a self,
+        from: &'a ImageKey,
+        to: &'a ImageKey,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            // The data is copied server-side, a missing source fails with 404. Copies take their
+            // own space, deduplicated images aren't smaller here, see `service::BlobRef`.
+            let source = format!("/{}/{}", self.bucket, uri_encode(from.as_str(), false));
+            let headers = [("x-amz-copy-source", source.as_str())];
+            self.send(Method::PUT, to.as_str(), &[], &headers, Vec::new())
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a ImageKey) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            // S3 answers 204 for missing keys too.
+            self.head(key).await?;
+            self.send(Method::DELETE, key.as_str(), &[], &[], Vec::new())
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list<'a>(
+        &'a self,
+        prefix: &'a ImageKey,
+        start_after: Option<&'a ImageKey>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Object>, Error>> {
+        async move {
+            let limit = limit.min(1000).to_string();
+            let mut query = vec![
+                ("list-type", "2"),
+                ("delimiter", "/"),
+                ("prefix", prefix.as_str()),
+                ("max-keys", limit.as_str()),
+            ];
+            if let Some(s) = start_after {
+                query.push(("start-after", s.as_str()));
+            }
+
+            let resp = self.send(Method::GET, "", &query, &[], Vec::new()).await?;
+            let xml = resp.text().await?;
+            let mut objects = Vec::new();
+            for contents in CONTENTS.captures_iter(&xml) {
+                let tag = |re: &Regex| {
+                    re.captures(&contents[1])
+                        .map(|c| xml_unescape(&c[1]))
+                        .ok_or_else(|| Error::S3("malformed list response".to_string()))
+                };
+
+                let key = tag(&KEY)?;
+                if !is_listed(&key, prefix, start_after) {
+                    continue;
+                }
+
+                // Skip objects which could not have been stored under a valid key.
+                let key = match ImageKey::parse(&key) {
+                    Ok(key) => key,
+                    Err(_) => continue,
+                };
+
+                objects.push(Object {
+                    key: key,
+                    size: tag(&SIZE)?
+                        .parse()
+                        .map_err(|_| Error::S3("invalid object size".to_string()))?,
+                    modified: humantime::parse_rfc3339_weak(&tag(&LAST_MODIFIED_TAG)?)
+                        .map_err(|_| Error::S3("invalid object timestamp".to_string()))?,
+                });
+            }
+
+            Ok(objects)
+        }
+        .boxed()
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key length");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// Formats the time as `YYYYMMDDTHHMMSSZ`.
+fn amz_date(t: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(t)
+        .to_string()
+        .replace('-', "")
+        .replace(':', "")
+}
+
+/// Percent-encodes everything except the unreserved characters, as required by SigV4.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut res = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                res.push(b as char)
+            }
+            b'/' if !encode_slash => res.push('/'),
+            _ => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
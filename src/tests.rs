@@ -1,10 +1,20 @@
 #[cfg(test)]
-use super::api;
+use super::key::{IdScheme, ImageKey};
+#[cfg(test)]
+use super::range::{self, Ranges};
+#[cfg(test)]
+use super::storage::{self, Storage};
+#[cfg(test)]
+use super::{api, auth, config, fetch, libvips, limit, service, webhook};
+#[cfg(test)]
+use futures::stream::TryStreamExt;
 #[cfg(test)]
 use hyper::service::{make_service_fn, service_fn};
 #[cfg(test)]
 use reqwest::blocking::{multipart::Form, Client};
 #[cfg(test)]
+use sha2::{Digest, Sha256};
+#[cfg(test)]
 use std::{fs::read, path::Path};
 #[cfg(test)]
 use tokio::runtime::Runtime;
@@ -13,7 +23,7 @@ use tokio::runtime::Runtime;
 fn get_img() {
     let _server = new_server(3000);
     let img = read(root().join("images").join("img_thumb.jpeg")).expect("read img");
-    let mut resp = Client::new()
+    let mut resp = client()
         .get("http://localhost:3000/images/img_thumb.jpeg")
         .send()
         .expect("request");
@@ -21,6 +31,22 @@ fn get_img() {
     let mut got = Vec::new();
     resp.copy_to(&mut got).expect("copy bytes");
     assert_eq!(img, got);
+
+    for path in &[
+        "img_thumb.jpeg",
+        "img_thumb.jpeg/original",
+        "img_thumb.jpeg/meta",
+    ] {
+        let url = format!("http://localhost:3000/images/{}", path);
+        for req in vec![client().put(&url), client().post(&url)] {
+            let resp = req.send().expect("request");
+            assert_eq!(resp.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+            assert!(resp.headers()["Allow"]
+                .to_str()
+                .expect("allow")
+                .starts_with("GET, HEAD"));
+        }
+    }
 }
 
 #[test]
@@ -31,6 +57,45 @@ fn store_json_base64_img() {
     let img = read(root().join("images").join("img.png")).expect("read img");
     store_json_img(port, filename, api::ImageData::Base64(base64::encode(&img)));
     check_file(filename);
+
+    let record = || -> service::Record {
+        let path = root().join(format!("images/.meta/{}.json", filename));
+        serde_json::from_slice(&read(path).expect("read record")).expect("deserialize record")
+    };
+    let stored = record();
+    assert_eq!(stored.original, format!(".originals/{}", filename));
+    let paths: Vec<_> = stored.derivatives.iter().map(|d| d.path.as_str()).collect();
+    assert_eq!(paths, vec![filename]);
+    assert_eq!(
+        stored.derivatives[0].options,
+        service::ThumbnailOptions::default()
+    );
+
+    let get = |path: &str| {
+        let mut resp = client()
+            .get(&format!("http://localhost:{}/images/{}", port, path))
+            .send()
+            .expect("request");
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let mut got = Vec::new();
+        resp.copy_to(&mut got).expect("copy bytes");
+        got
+    };
+    assert_eq!(get(&format!("{}/original", filename)), img);
+
+    // A derivative is recorded along with the thumbnail, and leaves the original alone.
+    get(&format!("{}?w=50", filename));
+    let derived = record();
+    assert_eq!(derived.original, stored.original);
+    assert_eq!(derived.derivatives.len(), 2);
+    assert_eq!(derived.derivatives[0].path, filename);
+    let derivative = &derived.derivatives[1];
+    assert!(derivative
+        .path
+        .starts_with(&format!(".cache/{}/", filename)));
+    assert_eq!(derivative.options.width, Some(50));
+    assert!(derivative.hash.is_some());
+    assert_eq!(get(&format!("{}/original", filename)), img);
 }
 
 #[test]
@@ -50,7 +115,7 @@ fn store_multipart_form_img() {
     let filename = "test_multipart_form.jpeg";
     let img_path = root().join("images/img.png");
     let form = Form::new().file(filename, img_path).expect("form");
-    let resp = Client::new()
+    let resp = client()
         .post(&format!("http://localhost:{}/images", port))
         .multipart(form)
         .send()
@@ -59,23 +124,1525 @@ fn store_multipart_form_img() {
     check_file(filename);
 }
 
+#[test]
+fn store_png_img() {
+    let port = 3004;
+    let _server = new_server(port);
+    let filename = "test_png.png";
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let mut img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    img_req.thumbnail = service::ThumbnailOptions {
+        format: libvips::Format::Png,
+        ..Default::default()
+    };
+    store_json_img_req(port, img_req);
+
+    let resp = client()
+        .get(&format!("http://localhost:{}/images/{}", port, filename))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.headers()["Content-Type"], "image/png");
+}
+
+#[test]
+fn get_resized_img() {
+    let port = 3005;
+    let _server = new_server(port);
+    let url = format!(
+        "http://localhost:{}/images/img_thumb.jpeg?w=50&fit=cover&fmt=png",
+        port
+    );
+    for _ in 0..2 {
+        let resp = client().get(&url).send().expect("request");
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.headers()["Content-Type"], "image/png");
+    }
+
+    let resp = client()
+        .get(&format!(
+            "http://localhost:{}/images/img_thumb.jpeg?w=0",
+            port
+        ))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // Unknown parameters are ignored, alone they leave the thumbnail as it is.
+    let thumb = read(root().join("images").join("img_thumb.jpeg")).expect("read img");
+    let resp = client()
+        .get(&format!(
+            "http://localhost:{}/images/img_thumb.jpeg?v=3&utm_source=cdn",
+            port
+        ))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.bytes().expect("body").as_ref(), &thumb[..]);
+    let resp = client()
+        .get(&format!(
+            "http://localhost:{}/images/img_thumb.jpeg?w=50&fmt=png&v=3",
+            port
+        ))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.headers()["Content-Type"], "image/png");
+
+    // The test config only allows a few sizes.
+    let resp = client()
+        .get(&format!(
+            "http://localhost:{}/images/img_thumb.jpeg?w=60",
+            port
+        ))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    let reason = resp.text().expect("response text");
+    assert!(reason.contains("10, 50, 100"), "{}", reason);
+}
+
+#[test]
+fn thumbnail_geometry() {
+    use libvips::{Crop, Size};
+
+    let thumbnail = |data: &[u8], width, height, crop, size| {
+        let geometry = libvips::Geometry {
+            width: width,
+            height: height,
+            crop: crop,
+            size: size,
+        };
+        let encoding = libvips::Encoding {
+            format: libvips::Format::Png,
+            quality: None,
+            lossless: false,
+            interlace: false,
+            effort: None,
+        };
+        let thumb = libvips::thumbnail(data.to_vec(), &geometry, &encoding).expect("thumbnail");
+        let header = libvips::header(&thumb).expect("read header");
+        (thumb, (header.width, header.height))
+    };
+
+    // A 200x100 source, the test image is square.
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let (src, dims) = thumbnail(&img, Some(200), Some(100), Crop::None, Size::Force);
+    assert_eq!(dims, (200, 100));
+
+    let cases = vec![
+        (None, None, Crop::None, Size::Both, (100, 50)),
+        (Some(50), None, Crop::None, Size::Both, (50, 25)),
+        (None, Some(50), Crop::None, Size::Both, (100, 50)),
+        (Some(50), Some(50), Crop::None, Size::Both, (50, 25)),
+        (Some(50), Some(50), Crop::Centre, Size::Both, (50, 50)),
+        (Some(50), None, Crop::Attention, Size::Both, (50, 50)),
+        (Some(60), Some(40), Crop::Entropy, Size::Both, (60, 40)),
+        (Some(50), Some(50), Crop::None, Size::Force, (50, 50)),
+        (Some(400), None, Crop::None, Size::Down, (200, 100)),
+        (Some(400), None, Crop::None, Size::Up, (400, 200)),
+        (Some(50), None, Crop::None, Size::Up, (200, 100)),
+    ];
+    for (width, height, crop, size, expected) in cases {
+        let (_, dims) = thumbnail(&src, width, height, crop, size);
+        assert_eq!(
+            dims, expected,
+            "{:?}x{:?} {:?} {:?}",
+            width, height, crop, size
+        );
+    }
+
+    // The options of an image request make it to libvips.
+    let port = 3027;
+    let _server = new_server(port);
+    let filename = "test_geometry.jpeg";
+    let mut img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    img_req.thumbnail.width = Some(60);
+    img_req.thumbnail.height = Some(40);
+    img_req.thumbnail.crop = Crop::Centre;
+    store_json_img_req(port, img_req);
+    let thumb = read(root().join("images").join(filename)).expect("read thumbnail");
+    let header = libvips::header(&thumb).expect("read header");
+    assert_eq!((header.width, header.height), (60, 40));
+}
+
+#[test]
+fn store_img_with_presets() {
+    let port = 3006;
+    let _server = new_server(port);
+    let filename = "test_presets.jpeg";
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let mut img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    img_req.presets = vec!["avatar".to_string()];
+    store_json_img_req(port, img_req);
+
+    let get = |preset: &str| {
+        client()
+            .get(&format!(
+                "http://localhost:{}/images/{}/{}",
+                port, filename, preset
+            ))
+            .send()
+            .expect("request")
+    };
+    let resp = get("avatar");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.headers()["Content-Type"], "image/webp");
+    assert_eq!(get("unknown").status(), reqwest::StatusCode::NOT_FOUND);
+
+    let mut img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    img_req.presets = vec!["unknown".to_string()];
+    let resp = store_json(port, vec![img_req]);
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn reject_path_traversal() {
+    let port = 3007;
+    let _server = new_server(port);
+    for path in &["..%2F..%2Fetc%2Fpasswd", ".meta", "a%5Cb"] {
+        let resp = client()
+            .get(&format!("http://localhost:{}/images/{}", port, path))
+            .send()
+            .expect("request");
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let img_req = image_request(
+        "../escaped.jpeg",
+        api::ImageData::Base64(base64::encode(&img)),
+    );
+    let resp = store_json(port, vec![img_req]);
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    assert!(!root().join("escaped.jpeg").exists());
+}
+
+#[test]
+fn name_conflicts() {
+    let port = 3008;
+    let _server = new_server(port);
+    let filename = "test_conflict.jpeg";
+    let _ = std::fs::remove_file(root().join("images").join("test_conflict-1.jpeg"));
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let data = || api::ImageData::Base64(base64::encode(&img));
+    store_json_img(port, filename, data());
+
+    let mut img_req = image_request(filename, data());
+    img_req.on_conflict = service::ConflictPolicy::Reject;
+    assert_eq!(
+        store_json(port, vec![img_req]).status(),
+        reqwest::StatusCode::CONFLICT
+    );
+
+    let mut img_req = image_request(filename, data());
+    img_req.on_conflict = service::ConflictPolicy::Suffix;
+    let resp = store_json(port, vec![img_req]);
+    check_img_resp("test_conflict-1.jpeg", resp);
+    check_file("test_conflict-1.jpeg");
+
+    let mut img_req = image_request(filename, data());
+    img_req.filename = None;
+    img_req.id = IdScheme::Hash;
+    let resp = store_json(port, vec![img_req]);
+    let name = format!("{}.jpeg", hex::encode(Sha256::digest(&img)));
+    check_img_resp(&name, resp);
+    check_file(&name);
+
+    let mut img_req = image_request(filename, data());
+    img_req.filename = None;
+    let resp = store_json(port, vec![img_req]);
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+    let resp_body: api::StoreImgResponseBody =
+        serde_json::de::from_str(&resp.text().expect("response text")).expect("deserialize resp");
+    let name = resp_body.0[0].filename.as_ref().expect("filename");
+    assert_eq!(name.len(), "01ARZ3NDEKTSV4RRFFQ69G5FAV.jpeg".len());
+    check_file(name);
+}
+
+#[test]
+fn delete_img() {
+    let port = 3009;
+    let _server = new_server(port);
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let data = || api::ImageData::Base64(base64::encode(&img));
+    let url = |path: &str| format!("http://localhost:{}/images{}", port, path);
+    for name in &["test_delete.jpeg", "test_batch_delete.jpeg"] {
+        store_json_img(port, name, data());
+    }
+
+    let resp = client()
+        .get(&url("/test_delete.jpeg?w=10"))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let cache = root().join("images/.cache/test_delete.jpeg");
+    assert!(cache.exists());
+
+    let delete = || {
+        client()
+            .delete(&url("/test_delete.jpeg"))
+            .send()
+            .expect("request")
+    };
+    let resp = delete();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let res: api::DeleteResult =
+        serde_json::de::from_str(&resp.text().expect("response text")).expect("deserialize");
+    assert_eq!(res.status, api::DeleteStatus::Deleted);
+    assert!(!root().join("images/test_delete.jpeg").exists());
+    assert!(!root().join("images/.originals/test_delete.jpeg").exists());
+    assert!(!cache.exists());
+    assert_eq!(delete().status(), reqwest::StatusCode::NOT_FOUND);
+    let resp = client()
+        .get(&url("/test_delete.jpeg"))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let names = vec![
+        "test_batch_delete.jpeg",
+        "test_delete.jpeg",
+        "../escaped.jpeg",
+    ];
+    let req_body = api::BatchDeleteRequestBody(names.iter().map(|n| n.to_string()).collect());
+    let resp = client()
+        .post(&url(":batchDelete"))
+        .header("Content-Type", "application/json")
+        .body(serde_json::ser::to_vec(&req_body).expect("json"))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let resp_body: api::BatchDeleteResponseBody =
+        serde_json::de::from_str(&resp.text().expect("response text")).expect("deserialize");
+    let statuses: Vec<_> = resp_body.0.iter().map(|r| &r.status).collect();
+    assert_eq!(
+        statuses,
+        vec![
+            &api::DeleteStatus::Deleted,
+            &api::DeleteStatus::NotFound,
+            &api::DeleteStatus::Error
+        ]
+    );
+    assert!(!root().join("images/test_batch_delete.jpeg").exists());
+}
+
+#[test]
+fn list_img() {
+    let port = 3010;
+    let _server = new_server(port);
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    for name in &["test_list_a.jpeg", "test_list_b.jpeg", "test_list_c.jpeg"] {
+        store_json_img(port, name, api::ImageData::Base64(base64::encode(&img)));
+    }
+
+    let list = |query: &str| {
+        let resp = client()
+            .get(&format!("http://localhost:{}/images?{}", port, query))
+            .send()
+            .expect("request");
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let text = resp.text().expect("response text");
+        serde_json::de::from_str::<api::ListResponseBody>(&text).expect("deserialize")
+    };
+    let names = |body: &api::ListResponseBody| {
+        body.images
+            .iter()
+            .map(|e| e.name.clone())
+            .collect::<Vec<_>>()
+    };
+
+    let page = list("prefix=test_list_&limit=2");
+    assert_eq!(names(&page), vec!["test_list_a.jpeg", "test_list_b.jpeg"]);
+    assert_eq!(
+        page.next_cursor.as_ref().map(String::as_str),
+        Some("test_list_b.jpeg")
+    );
+    let entry = &page.images[0];
+    assert_eq!(entry.format, Some(libvips::Format::Jpeg));
+    assert_eq!(entry.width.max(entry.height), Some(100));
+
+    let page = list("prefix=test_list_&limit=2&cursor=test_list_b.jpeg");
+    assert_eq!(names(&page), vec!["test_list_c.jpeg"]);
+    assert_eq!(page.next_cursor, None);
+
+    let page = list("prefix=img_thumb");
+    assert_eq!(names(&page), vec!["img_thumb.jpeg"]);
+    assert_eq!(page.images[0].format, Some(libvips::Format::Jpeg));
+
+    let resp = client()
+        .get(&format!("http://localhost:{}/images?limit=0", port))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn head_img_and_meta() {
+    let port = 3011;
+    let _server = new_server(port);
+    let filename = "test_meta.jpeg";
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    store_json_img(port, filename, api::ImageData::Base64(base64::encode(&img)));
+    let url = |path: &str| format!("http://localhost:{}/images/{}", port, path);
+
+    let resp = client().head(&url(filename)).send().expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let thumb = read(root().join("images").join(filename)).expect("read thumb");
+    let headers = resp.headers();
+    assert_eq!(headers["Content-Type"], "image/jpeg");
+    assert_eq!(headers["Content-Length"], thumb.len().to_string().as_str());
+    let etag = format!("\"{}\"", hex::encode(Sha256::digest(&thumb)));
+    assert_eq!(headers["ETag"], etag.as_str());
+    assert!(headers.contains_key("Last-Modified"));
+
+    let resp = client()
+        .head(&url("test_missing.jpeg"))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let resp = client()
+        .get(&url(&format!("{}/meta", filename)))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let meta: api::MetaResponseBody =
+        serde_json::de::from_str(&resp.text().expect("response text")).expect("deserialize");
+    assert_eq!(meta.width.max(meta.height), 100);
+    assert_eq!(meta.bands, 3);
+    assert_eq!(meta.format, Some(libvips::Format::Jpeg));
+    assert_eq!(meta.color_space, "srgb");
+    assert_eq!(meta.size, thumb.len() as u64);
+    assert!(!meta.has_alpha);
+    assert_eq!(meta.options, Some(Default::default()));
+}
+
+#[test]
+fn conditional_get() {
+    let port = 3012;
+    let _server = new_server(port);
+    let filename = "test_cache.jpeg";
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    store_json_img(port, filename, api::ImageData::Base64(base64::encode(&img)));
+
+    let get = |path: &str, header: Option<(&str, &str)>| {
+        let mut req = client().get(&format!("http://localhost:{}/images/{}", port, path));
+        if let Some((name, value)) = header {
+            req = req.header(name, value);
+        }
+        req.send().expect("request")
+    };
+
+    for path in &[filename.to_string(), format!("{}?w=50", filename)] {
+        let resp = get(path, None);
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.headers()["Cache-Control"], "public, max-age=86400");
+        let etag = resp.headers()["ETag"].to_str().expect("etag").to_string();
+        let modified = resp.headers()["Last-Modified"]
+            .to_str()
+            .expect("last modified")
+            .to_string();
+
+        let resp = get(path, Some(("If-None-Match", &etag)));
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()["ETag"], etag.as_str());
+        let resp = get(path, Some(("If-None-Match", &format!("W/{}", etag))));
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_MODIFIED);
+        let resp = get(path, Some(("If-None-Match", "\"other\"")));
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        let resp = get(path, Some(("If-Modified-Since", &modified)));
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_MODIFIED);
+        let resp = get(
+            path,
+            Some(("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")),
+        );
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    }
+
+    // Storing the same data again shares the stored objects, the image is newer all the same.
+    let modified = get(filename, None).headers()["Last-Modified"]
+        .to_str()
+        .expect("last modified")
+        .to_string();
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    assert_eq!(
+        store_json(port, vec![img_req]).status(),
+        reqwest::StatusCode::CREATED
+    );
+    let resp = get(filename, Some(("If-Modified-Since", &modified)));
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_ne!(resp.headers()["Last-Modified"], modified.as_str());
+}
+
+#[test]
+fn range_get() {
+    let port = 3013;
+    let _server = new_server(port);
+    let filename = "test_range.jpeg";
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    store_json_img(port, filename, api::ImageData::Base64(base64::encode(&img)));
+    let thumb = read(root().join("images").join(filename)).expect("read thumb");
+
+    let get = |headers: &[(&str, &str)]| {
+        let mut req = client().get(&format!("http://localhost:{}/images/{}", port, filename));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.send().expect("request")
+    };
+    let body = |mut resp: reqwest::blocking::Response| {
+        let mut got = Vec::new();
+        resp.copy_to(&mut got).expect("copy bytes");
+        got
+    };
+
+    let resp = get(&[]);
+    assert_eq!(resp.headers()["Accept-Ranges"], "bytes");
+    let etag = resp.headers()["ETag"].to_str().expect("etag").to_string();
+
+    let resp = get(&[("Range", "bytes=10-19")]);
+    assert_eq!(resp.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    let content_range = format!("bytes 10-19/{}", thumb.len());
+    assert_eq!(resp.headers()["Content-Range"], content_range.as_str());
+    assert_eq!(body(resp), &thumb[10..20]);
+
+    let resp = get(&[("Range", "bytes=0-1,-2")]);
+    assert_eq!(resp.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    let content_type = resp.headers()["Content-Type"].to_str().expect("type");
+    assert!(content_type.starts_with("multipart/byteranges; boundary="));
+    let got = body(resp);
+    let tail = format!(
+        "bytes {}-{}/{}",
+        thumb.len() - 2,
+        thumb.len() - 1,
+        thumb.len()
+    );
+    for part in &["Content-Range: bytes 0-1/", tail.as_str()] {
+        assert!(got.windows(part.len()).any(|w| w == part.as_bytes()));
+    }
+
+    let resp = get(&[("Range", &format!("bytes={}-", thumb.len()))]);
+    assert_eq!(resp.status(), reqwest::StatusCode::RANGE_NOT_SATISFIABLE);
+    let content_range = format!("bytes */{}", thumb.len());
+    assert_eq!(resp.headers()["Content-Range"], content_range.as_str());
+
+    let resp = get(&[("Range", "bytes=0-9"), ("If-Range", &etag)]);
+    assert_eq!(resp.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    let resp = get(&[("Range", "bytes=0-9"), ("If-Range", "\"other\"")]);
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(body(resp), thumb);
+}
+
+#[test]
+fn stream_original() {
+    let port = 3014;
+    let _server = new_server(port);
+    let filename = "test_stream.jpeg";
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    store_json_img(port, filename, api::ImageData::Base64(base64::encode(&img)));
+
+    let url = format!("http://localhost:{}/images/{}/original", port, filename);
+    let mut resp = client().get(&url).send().expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.headers()["Content-Type"], "image/png");
+    let content_length = img.len().to_string();
+    assert_eq!(resp.headers()["Content-Length"], content_length.as_str());
+    let mut got = Vec::new();
+    resp.copy_to(&mut got).expect("copy bytes");
+    assert_eq!(got, img);
+
+    let resp = client().head(&url).send().expect("request");
+    assert_eq!(resp.headers()["Content-Type"], "image/png");
+    assert_eq!(resp.headers()["Content-Length"], content_length.as_str());
+}
+
+#[test]
+fn upload_limits() {
+    let port = 3015;
+    let _server = new_server(port);
+    let url = format!("http://localhost:{}/images", port);
+    let img_path = root().join("images/img.png");
+
+    let form = (0..5).fold(Form::new(), |form, i| {
+        let filename = format!("test_limits_{}.jpeg", i);
+        form.file(filename, &img_path).expect("form")
+    });
+    let resp = client().post(&url).multipart(form).send().expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+    let big = vec![0; 300 * 1024];
+    let part = reqwest::blocking::multipart::Part::bytes(big).file_name("big.png");
+    let form = Form::new().part("test_limits_big.jpeg", part);
+    let resp = client().post(&url).multipart(form).send().expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+    let img = read(&img_path).expect("read img");
+    let imgs = (0..5)
+        .map(|i| {
+            let filename = format!("test_limits_{}.jpeg", i);
+            image_request(&filename, api::ImageData::Base64(base64::encode(&img)))
+        })
+        .collect();
+    let resp = store_json(port, imgs);
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+    let big = "0".repeat(1100 * 1024);
+    let resp = client()
+        .post(&url)
+        .header("Content-Type", "application/json")
+        .body(big)
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(!root().join("images/test_limits_0.jpeg").exists());
+}
+
+#[test]
+fn batch_results() {
+    let port = 3020;
+    let _server = new_server(port);
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let good = |name: &str| image_request(name, api::ImageData::Base64(base64::encode(&img)));
+    let bad = |name: &str| image_request(name, api::ImageData::Base64("not base64".to_string()));
+    let store = |mode: &str, imgs: Vec<api::ImageRequest>| {
+        let resp = client()
+            .post(&format!("http://localhost:{}/images?mode={}", port, mode))
+            .header("Content-Type", "application/json")
+            .body(serde_json::ser::to_vec(&api::StoreImgRequestBody(imgs)).expect("json"))
+            .send()
+            .expect("request");
+        let status = resp.status();
+        let resp_body: api::StoreImgResponseBody =
+            serde_json::de::from_str(&resp.text().expect("response text"))
+                .expect("deserialize resp");
+        let statuses: Vec<_> = resp_body.0.iter().map(|img| img.status).collect();
+        (status, statuses, resp_body)
+    };
+    use api::StoreStatus::*;
+
+    // Items are processed in parallel, results come in the order of the request.
+    let names = [
+        "test_batch_1.jpeg",
+        "test_batch_2.jpeg",
+        "test_batch_3.jpeg",
+        "test_batch_7.jpeg",
+    ];
+    let imgs = vec![
+        good(names[0]),
+        bad(names[1]),
+        good(names[2]),
+        good(names[3]),
+    ];
+    let (status, statuses, resp_body) = store("best_effort", imgs);
+    assert_eq!(status, reqwest::StatusCode::MULTI_STATUS);
+    assert_eq!(statuses, vec![Created, Error, Created, Created]);
+    assert_eq!(resp_body.0[1].code, Some(400));
+    let got: Vec<_> = resp_body
+        .0
+        .iter()
+        .map(|img| img.filename.as_ref().map(String::as_str))
+        .collect();
+    assert_eq!(got, names.iter().map(|n| Some(*n)).collect::<Vec<_>>());
+    check_file("test_batch_7.jpeg");
+    check_file("test_batch_1.jpeg");
+    check_file("test_batch_3.jpeg");
+    assert!(!root().join("images/test_batch_2.jpeg").exists());
+
+    let _ = std::fs::remove_file(root().join("images/test_batch_4.jpeg"));
+    // An unknown preset fails before the image is even read, so the last image is never started.
+    let mut invalid = good("test_batch_5.jpeg");
+    invalid.presets = vec!["unknown".to_string()];
+    let imgs = vec![
+        good("test_batch_4.jpeg"),
+        invalid,
+        good("test_batch_6.jpeg"),
+    ];
+    let (status, statuses, _) = store("atomic", imgs);
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+    assert_eq!(statuses, vec![RolledBack, Error, Skipped]);
+    for name in &["test_batch_4.jpeg", "test_batch_6.jpeg"] {
+        assert!(!root().join("images").join(name).exists(), "{}", name);
+        assert!(!root().join("images/.originals").join(name).exists());
+    }
+
+    let (status, statuses, _) = store("atomic", vec![good("test_batch_4.jpeg")]);
+    assert_eq!(status, reqwest::StatusCode::CREATED);
+    assert_eq!(statuses, vec![Created]);
+    check_file("test_batch_4.jpeg");
+
+    // Rolling back an image restores the one it replaced.
+    let mut replacement = good("test_batch_4.jpeg");
+    replacement.thumbnail.width = Some(50);
+    let (status, statuses, _) = store("atomic", vec![replacement, bad("test_batch_5.jpeg")]);
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+    assert_eq!(statuses, vec![RolledBack, Error]);
+    check_file("test_batch_4.jpeg");
+    let original = read(root().join("images/.originals/test_batch_4.jpeg")).expect("read original");
+    assert_eq!(original, img);
+    let resp = client()
+        .get(&format!(
+            "http://localhost:{}/images/test_batch_4.jpeg",
+            port
+        ))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let expected = read(root().join("images/img_thumb.jpeg")).expect("read img");
+    assert_eq!(resp.bytes().expect("body").as_ref(), &expected[..]);
+}
+
+#[test]
+fn vips_bound() {
+    let port = 3024;
+    let _server = new_server(port);
+    let limits = &config::CONFIG.processing;
+    assert!(limits.batch_concurrency > limits.vips_concurrency);
+
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let imgs = (0..limits.batch_concurrency)
+        .map(|i| {
+            let filename = format!("test_vips_bound_{}.jpeg", i);
+            // Deleted first, so earlier runs leave no thumbnail to deduplicate against.
+            client()
+                .delete(&format!("http://localhost:{}/images/{}", port, filename))
+                .send()
+                .expect("request");
+            let mut img_req =
+                image_request(&filename, api::ImageData::Base64(base64::encode(&img)));
+            // Different sizes, so no thumbnail is deduplicated.
+            img_req.thumbnail.width = Some(20 + i as u32);
+            img_req
+        })
+        .collect();
+    let resp = store_json(port, imgs);
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+
+    // Other tests thumbnail at the same time, the bound is server-wide all the same.
+    let peak = VIPS_LOAD.lock().expect("lock vips load").1;
+    assert!(peak >= 1);
+    assert!(peak <= limits.vips_concurrency, "{}", peak);
+}
+
+#[test]
+fn jobs() {
+    let port = 3021;
+    let _server = new_server(port);
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let imgs = vec![
+        image_request(
+            "test_job_1.jpeg",
+            api::ImageData::Base64(base64::encode(&img)),
+        ),
+        image_request(
+            "test_job_2.jpeg",
+            api::ImageData::Base64("not base64".to_string()),
+        ),
+    ];
+    let resp = client()
+        .post(&format!("http://localhost:{}/jobs?mode=best_effort", port))
+        .header("Content-Type", "application/json")
+        .body(serde_json::ser::to_vec(&api::StoreImgRequestBody(imgs)).expect("json"))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::ACCEPTED);
+    let location = resp.headers()["Location"]
+        .to_str()
+        .expect("location")
+        .to_string();
+    let job: api::jobs::Job =
+        serde_json::de::from_str(&resp.text().expect("response text")).expect("deserialize job");
+    assert_eq!(location, format!("/jobs/{}", job.id));
+    assert_eq!(job.results.len(), 2);
+
+    let get = |path: &str| {
+        let resp = client()
+            .get(&format!("http://localhost:{}{}", port, path))
+            .send()
+            .expect("request");
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let job: api::jobs::Job = serde_json::de::from_str(&resp.text().expect("response text"))
+            .expect("deserialize job");
+        job
+    };
+    let mut job = get(&location);
+    for _ in 0..100 {
+        if job.status == api::jobs::JobStatus::Done {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        job = get(&location);
+    }
+    assert_eq!(job.status, api::jobs::JobStatus::Done);
+    assert_eq!(job.processed, 2);
+    let statuses: Vec<_> = job
+        .results
+        .iter()
+        .map(|res| res.as_ref().expect("result").status)
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![api::StoreStatus::Created, api::StoreStatus::Error]
+    );
+    check_file("test_job_1.jpeg");
+
+    // A job saved as running by a server that crashed.
+    let id = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+    let running = api::jobs::Job {
+        id: id.to_string(),
+        status: api::jobs::JobStatus::Running,
+        results: vec![None],
+        processed: 0,
+        ..job
+    };
+    let jobs_folder = root().join("images/.jobs");
+    std::fs::create_dir_all(&jobs_folder).expect("create jobs folder");
+    let json = serde_json::ser::to_vec(&running).expect("json");
+    std::fs::write(jobs_folder.join(format!("{}.json", id)), json).expect("write job");
+    let job = get(&format!("/jobs/{}", id));
+    assert_eq!(job.status, api::jobs::JobStatus::Interrupted);
+
+    // Jobs expire once they are done, or were interrupted.
+    Runtime::new()
+        .expect("make runtime")
+        .block_on(api::jobs::expire(std::time::Duration::from_secs(0)));
+    for path in &[location, format!("/jobs/{}", id)] {
+        let resp = client()
+            .get(&format!("http://localhost:{}{}", port, path))
+            .send()
+            .expect("request");
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    let resp = client()
+        .get(&format!(
+            "http://localhost:{}/jobs/{}",
+            port,
+            "0".repeat(26)
+        ))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn decode_limits() {
+    let port = 3016;
+    let _server = new_server(port);
+    let filename = "test_bomb.jpeg";
+
+    // Declare 50000x50000 pixels in the header of a small PNG.
+    let mut img = read(root().join("images").join("img.png")).expect("read img");
+    img[16..20].copy_from_slice(&50_000u32.to_be_bytes());
+    img[20..24].copy_from_slice(&50_000u32.to_be_bytes());
+    let crc = crc32(&img[12..29]);
+    img[29..33].copy_from_slice(&crc.to_be_bytes());
+
+    let img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    let resp = store_json(port, vec![img_req]);
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    let reason = resp.text().expect("response text");
+    assert!(reason.contains("50000x50000"), "{}", reason);
+    assert!(!root().join("images").join(filename).exists());
+
+    // A PNG signature followed by garbage is the client's fault as well.
+    let mut img = read(root().join("images").join("img.png")).expect("read img");
+    img.truncate(8);
+    img.extend_from_slice(&[0xAB; 64]);
+    let img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    let resp = store_json(port, vec![img_req]);
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    assert!(!root().join("images").join(filename).exists());
+}
+
+#[test]
+fn input_formats() {
+    let port = 3017;
+    let _server = new_server(port);
+    let filename = "test_input_format.jpeg";
+
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    let resp = store_json(port, vec![img_req]);
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+    let resp_body: api::StoreImgResponseBody =
+        serde_json::de::from_str(&resp.text().expect("response text")).expect("deserialize resp");
+    assert_eq!(resp_body.0[0].input_format, Some(libvips::InputFormat::Png));
+
+    // GIF is left out of the allowed formats of the test config only.
+    let production: config::Config =
+        serde_json::from_slice(&read(root().join("config.json")).expect("read config"))
+            .expect("parse config");
+    assert!(production
+        .input_formats
+        .contains(&libvips::InputFormat::Gif));
+    let unsupported: &[&[u8]] = &[b"GIF89a\x01\x00\x01\x00", b"not an image at all"];
+    for data in unsupported {
+        let img_req = image_request(filename, api::ImageData::Bytes(data.to_vec()));
+        let resp = store_json(port, vec![img_req]);
+        assert_eq!(resp.status(), reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let detect = libvips::InputFormat::detect;
+    assert_eq!(
+        detect(b"\xFF\xD8\xFF\xE0"),
+        Some(libvips::InputFormat::Jpeg)
+    );
+    assert_eq!(detect(b"II*\0\x08\0\0\0"), Some(libvips::InputFormat::Tiff));
+    assert_eq!(
+        detect(b"\0\0\0\x18ftypheic\0\0\0\0"),
+        Some(libvips::InputFormat::Heif)
+    );
+    assert_eq!(
+        detect(b"\0\0\0\x1cftypavif\0\0\0\0"),
+        Some(libvips::InputFormat::Avif)
+    );
+    assert_eq!(detect(b"RIFF\0\0\0\0WAVE"), None);
+}
+
+#[test]
+fn fetch_policy() {
+    let port = 3018;
+    let _server = new_server(port);
+    let remote = 3019;
+    let _remote = new_remote(remote);
+
+    let store = |uri: &str| {
+        let img_req = image_request("test_fetch.jpeg", api::ImageData::URI(uri.to_string()));
+        store_json(port, vec![img_req]).status()
+    };
+    let blocked = [
+        format!("http://127.0.0.1:{}/img.png", remote),
+        format!("http://localhost:{}/img.png", remote),
+        format!("http://[::ffff:127.0.0.1]:{}/img.png", remote),
+        "http://169.254.169.254/latest/meta-data/".to_string(),
+        "http://10.0.0.1/img.png".to_string(),
+        "file:///etc/passwd".to_string(),
+    ];
+    for uri in &blocked {
+        assert_eq!(store(uri), reqwest::StatusCode::BAD_REQUEST, "{}", uri);
+    }
+
+    let policy = config::FetchPolicy {
+        allow_private_ips: true,
+        denied_hosts: vec!["denied.localhost".to_string()],
+        max_redirects: 2,
+        timeout_secs: 1,
+        ..Default::default()
+    };
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let url = |path: &str| format!("http://127.0.0.1:{}{}", remote, path);
+    let get = |policy: &config::FetchPolicy, path: &str| {
+        Runtime::new()
+            .expect("make runtime")
+            .block_on(fetch::fetch(policy, &url(path)))
+    };
+
+    assert_eq!(get(&policy, "/img.png").expect("fetch"), img);
+    assert_eq!(get(&policy, "/redirect/2").expect("fetch"), img);
+    assert_eq!(get(&policy, "/flaky").expect("fetch"), img);
+    for path in &["/redirect/3", "/denied", "/text", "/missing", "/slow"] {
+        assert!(get(&policy, path).is_err(), "{}", path);
+    }
+
+    let small = config::FetchPolicy {
+        max_size: 1000,
+        ..policy
+    };
+    assert!(get(&small, "/img.png").is_err());
+
+    // Connections only go to addresses checked when connecting.
+    let resolve = |allow_private_ips: bool| {
+        use hyper::client::connect::dns::Resolve;
+        let resolver = fetch::CheckedResolver {
+            allow_private_ips: allow_private_ips,
+        };
+        Runtime::new()
+            .expect("make runtime")
+            .block_on(resolver.resolve("localhost".parse().expect("name")))
+    };
+    assert!(resolve(false).is_err());
+    assert!(resolve(true).expect("resolve").any(|ip| ip.is_loopback()));
+
+    assert!(!fetch::is_public("192.168.1.1".parse().expect("ip")));
+    assert!(!fetch::is_public("100.64.0.1".parse().expect("ip")));
+    assert!(!fetch::is_public("fe80::1".parse().expect("ip")));
+    assert!(!fetch::is_public("fd00::1".parse().expect("ip")));
+    assert!(fetch::is_public("93.184.216.34".parse().expect("ip")));
+    assert!(fetch::is_public("2606:2800:220:1::1".parse().expect("ip")));
+}
+
+#[test]
+fn webhooks() {
+    let port = 3022;
+    let (_receiver, received) = new_receiver(port);
+    let url = |path: &str| format!("http://127.0.0.1:{}{}", port, path);
+    let endpoint = |path: &str| config::WebhookEndpoint {
+        url: url(path),
+        secret: "s3cret".to_string(),
+        events: vec![webhook::EventKind::Created],
+    };
+    let cfg = config::WebhookConfig {
+        retries: 2,
+        retry_backoff_ms: 10,
+        timeout_secs: 1,
+        ..Default::default()
+    };
+    let dead_letters = root().join("images/.webhooks/test_dead_letters.jsonl");
+    let _ = std::fs::remove_file(&dead_letters);
+    let key = ImageKey::parse_name("test_webhook.jpeg").expect("key");
+    let event = webhook::Event::new(webhook::EventKind::Created, &key);
+    let deliver = |endpoint: &config::WebhookEndpoint| {
+        Runtime::new()
+            .expect("make runtime")
+            .block_on(webhook::deliver(&cfg, endpoint, &event, &dead_letters))
+    };
+
+    assert!(endpoint("/ok").subscribes(webhook::EventKind::Created));
+    assert!(!endpoint("/ok").subscribes(webhook::EventKind::Deleted));
+
+    deliver(&endpoint("/ok")).expect("deliver");
+    {
+        let received = received.lock().expect("lock received");
+        assert_eq!(received.len(), 1);
+        let (path, headers, body) = &received[0];
+        assert_eq!(path, "/ok");
+        let header = |name: &str| headers[name].to_str().expect("header").to_string();
+        let signature = webhook::signature("s3cret", &header("X-Webhook-Timestamp"), body);
+        assert_eq!(header("X-Webhook-Signature"), signature);
+        assert_eq!(header("X-Webhook-Id"), event.id);
+        let got: webhook::Event = serde_json::from_slice(body).expect("deserialize event");
+        assert_eq!(got, event);
+    }
+    assert!(!dead_letters.exists());
+
+    assert!(deliver(&endpoint("/fail")).is_err());
+    let attempts = received
+        .lock()
+        .expect("lock received")
+        .iter()
+        .filter(|(path, _, _)| path == "/fail")
+        .count();
+    assert_eq!(attempts, 3);
+    let log = std::fs::read_to_string(&dead_letters).expect("read dead letters");
+    let letters: Vec<webhook::DeadLetter> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("deserialize dead letter"))
+        .collect();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].event, event);
+    assert_eq!(letters[0].endpoint, url("/fail"));
+    assert_eq!(letters[0].attempts, 3);
+}
+
+#[test]
+fn webhook_events() {
+    let port = 3025;
+    // The endpoint of the test config.
+    let (_receiver, received) = new_receiver(3026);
+    let _server = new_server(port);
+    let filename = format!("test_events_{}.jpeg", std::process::id());
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let data = || api::ImageData::Base64(base64::encode(&img));
+
+    store_json_img(port, &filename, data());
+    let resp = store_json(port, vec![image_request(&filename, data())]);
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+    let resp = client()
+        .delete(&format!("http://localhost:{}/images/{}", port, filename))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+    // Deliveries run in the background, and may arrive in any order.
+    let mut events = Vec::new();
+    for _ in 0..50 {
+        events = received
+            .lock()
+            .expect("lock received")
+            .iter()
+            .filter(|(path, _, _)| path == "/events")
+            .map(|(_, headers, body)| {
+                let header = |name: &str| headers[name].to_str().expect("header").to_string();
+                let signature =
+                    webhook::signature("test-secret", &header("X-Webhook-Timestamp"), body);
+                assert_eq!(header("X-Webhook-Signature"), signature);
+                let event: webhook::Event =
+                    serde_json::from_slice(body).expect("deserialize event");
+                assert_eq!(header("X-Webhook-Id"), event.id);
+                event
+            })
+            .filter(|event| event.filename == filename)
+            .collect();
+        if events.len() >= 3 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    use webhook::EventKind::*;
+    assert_eq!(events.len(), 3);
+    for kind in &[Created, Replaced, Deleted] {
+        assert!(events.iter().any(|event| event.kind == *kind), "{:?}", kind);
+    }
+}
+
+#[test]
+fn api_keys() {
+    use auth::{Error, Scope};
+
+    let key = |key: &str, scopes: Vec<Scope>, expires_at: Option<&str>| config::ApiKey {
+        name: key.to_string(),
+        key: key.to_string(),
+        scopes: scopes,
+        expires_at: expires_at.map(str::to_string),
+    };
+    let cfg = config::AuthConfig {
+        keys: vec![
+            key("reader", vec![Scope::Read], None),
+            key(
+                "writer",
+                vec![Scope::Read, Scope::Write],
+                Some("2999-01-01T00:00:00Z"),
+            ),
+            key("admin", vec![Scope::Admin], None),
+            key("expired", vec![Scope::Admin], Some("2019-01-01T00:00:00Z")),
+        ],
+        ..Default::default()
+    };
+    let authorize = |header: Option<(&str, &str)>, scope: Scope| {
+        let mut headers = hyper::HeaderMap::new();
+        if let Some((name, value)) = header {
+            headers.insert(
+                hyper::header::HeaderName::from_bytes(name.as_bytes()).expect("name"),
+                value.parse().expect("value"),
+            );
+        }
+        auth::authorize(&cfg, &headers, scope)
+    };
+
+    assert_eq!(authorize(None, Scope::Read), Err(Error::Missing));
+    assert_eq!(
+        authorize(Some(("Authorization", "Bearer nope")), Scope::Read),
+        Err(Error::Invalid)
+    );
+    assert_eq!(
+        authorize(Some(("Authorization", "Bearer reader")), Scope::Read),
+        Ok(())
+    );
+    assert_eq!(
+        authorize(Some(("X-Api-Key", "reader")), Scope::Write),
+        Err(Error::Forbidden(Scope::Write))
+    );
+    assert_eq!(
+        authorize(Some(("X-Api-Key", "writer")), Scope::Write),
+        Ok(())
+    );
+    assert_eq!(
+        authorize(Some(("X-Api-Key", "writer")), Scope::Delete),
+        Err(Error::Forbidden(Scope::Delete))
+    );
+    assert_eq!(
+        authorize(Some(("X-Api-Key", "admin")), Scope::Delete),
+        Ok(())
+    );
+    assert_eq!(
+        authorize(Some(("X-Api-Key", "expired")), Scope::Read),
+        Err(Error::Expired)
+    );
+
+    let open = config::AuthConfig {
+        open: true,
+        ..Default::default()
+    };
+    let headers = hyper::HeaderMap::new();
+    assert_eq!(auth::authorize(&open, &headers, Scope::Admin), Ok(()));
+
+    // Images that need a key to be read are kept out of shared caches.
+    let cache_control = |auth: &str| {
+        let json = format!(r#"{{"cache_control": "public", "auth": {}}}"#, auth);
+        let cfg: config::Config = serde_json::from_str(&json).expect("parse config");
+        cfg.cache_control().map(str::to_string)
+    };
+    let keys = r#"[{"key": "reader", "scopes": ["read"]}]"#;
+    assert_eq!(
+        cache_control(&format!(r#"{{"keys": {}}}"#, keys)),
+        Some("private".to_string())
+    );
+    assert_eq!(
+        cache_control(&format!(r#"{{"keys": {}, "public_reads": true}}"#, keys)),
+        Some("public".to_string())
+    );
+    assert_eq!(
+        cache_control(r#"{"open": true}"#),
+        Some("public".to_string())
+    );
+
+    // The shipped config reads the keys docker-compose mounts, the example being a valid key file.
+    let production: config::Config =
+        serde_json::from_slice(&read(root().join("config.json")).expect("read config"))
+            .expect("parse config");
+    assert_eq!(
+        production.auth.key_file.as_ref().map(String::as_str),
+        Some("/run/secrets/api_keys")
+    );
+    let example = read(root().join("secrets/api_keys.example.json")).expect("read key file");
+    let keys: Vec<config::ApiKey> = serde_json::from_slice(&example).expect("parse key file");
+    assert!(!keys.is_empty());
+    assert!(!production.auth.open);
+}
+
+#[test]
+fn authenticate_requests() {
+    let port = 3023;
+    let _server = new_server(port);
+    let url = |path: &str| format!("http://localhost:{}{}", port, path);
+    let filename = "test_auth.jpeg";
+    let _ = std::fs::remove_file(root().join("images").join(filename));
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let img_req = image_request(filename, api::ImageData::Base64(base64::encode(&img)));
+    let body = serde_json::ser::to_vec(&api::StoreImgRequestBody(vec![img_req])).expect("json");
+    let store = |key: Option<&str>| {
+        let mut req = Client::new()
+            .post(&url("/images"))
+            .header("Content-Type", "application/json")
+            .body(body.clone());
+        if let Some(key) = key {
+            req = req.header("Authorization", format!("Bearer {}", key));
+        }
+        req.send().expect("request")
+    };
+    let error = |resp: reqwest::blocking::Response| {
+        let status = resp.status();
+        let www_authenticate = resp.headers().get("WWW-Authenticate").cloned();
+        let body: api::ErrorResponseBody =
+            serde_json::de::from_str(&resp.text().expect("response text"))
+                .expect("deserialize resp");
+        (status, www_authenticate, body)
+    };
+
+    let (status, www_authenticate, body) = error(store(None));
+    assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+    assert_eq!(www_authenticate.expect("www-authenticate"), "Bearer");
+    assert_eq!(body.code, 401);
+    assert_eq!(body.reason, "missing api key");
+
+    let (status, _, body) = error(store(Some("nope")));
+    assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+    assert_eq!(body.reason, "invalid api key");
+
+    let (status, www_authenticate, body) = error(store(Some("test-read-key")));
+    assert_eq!(status, reqwest::StatusCode::FORBIDDEN);
+    assert!(www_authenticate.is_none());
+    assert_eq!(body.code, 403);
+    assert_eq!(body.reason, "api key lacks the write scope");
+    assert!(!root().join("images").join(filename).exists());
+
+    check_img_resp(filename, store(Some(ADMIN_KEY)));
+
+    // The test config lets anyone read images, but nothing else.
+    let resp = Client::new()
+        .get(&url(&format!("/images/{}", filename)))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let resp = Client::new()
+        .get(&url(&format!("/images/{}/meta", filename)))
+        .send()
+        .expect("request");
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let resp = Client::new().get(&url("/images")).send().expect("request");
+    assert_eq!(error(resp).0, reqwest::StatusCode::UNAUTHORIZED);
+    let resp = Client::new()
+        .delete(&url(&format!("/images/{}", filename)))
+        .send()
+        .expect("request");
+    assert_eq!(error(resp).0, reqwest::StatusCode::UNAUTHORIZED);
+    let resp = Client::new()
+        .delete(&url(&format!("/images/{}", filename)))
+        .header("X-Api-Key", "test-read-key")
+        .send()
+        .expect("request");
+    assert_eq!(error(resp).0, reqwest::StatusCode::FORBIDDEN);
+    check_file(filename);
+}
+
+#[test]
+fn limiter() {
+    use std::time::Duration;
+    use tokio::timer::Timeout;
+
+    let limiter = limit::Limiter::new(1);
+    Runtime::new().expect("make runtime").block_on(async {
+        let first = limiter.acquire().await;
+        let waiting = Timeout::new(limiter.acquire(), Duration::from_millis(50)).await;
+        assert!(waiting.is_err(), "second permit");
+
+        drop(first);
+        let again = Timeout::new(limiter.acquire(), Duration::from_millis(50)).await;
+        let again = again.expect("permit released");
+        assert!(!limiter.is_idle());
+        drop(again);
+        assert!(limiter.is_idle());
+    });
+
+    assert_eq!(limit::backoff(200, 0), Duration::from_millis(200));
+    assert_eq!(limit::backoff(200, 2), Duration::from_millis(800));
+    assert_eq!(limit::backoff(1, 40), Duration::from_millis(1 << 16));
+    assert_eq!(
+        limit::backoff(u64::max_value(), 3).as_millis(),
+        u64::max_value() as u128
+    );
+}
+
+#[test]
+fn byte_ranges() {
+    assert_eq!(range::parse("bytes=0-9", 100), Ranges::Partial(vec![0..10]));
+    assert_eq!(
+        range::parse("bytes=90-", 100),
+        Ranges::Partial(vec![90..100])
+    );
+    assert_eq!(
+        range::parse("bytes=-10", 100),
+        Ranges::Partial(vec![90..100])
+    );
+    assert_eq!(
+        range::parse("bytes=-200", 100),
+        Ranges::Partial(vec![0..100])
+    );
+    assert_eq!(
+        range::parse("bytes=95-200", 100),
+        Ranges::Partial(vec![95..100])
+    );
+    assert_eq!(
+        range::parse("bytes=0-0, 200-, 5-6", 100),
+        Ranges::Partial(vec![0..1, 5..7])
+    );
+    assert_eq!(range::parse("bytes=100-", 100), Ranges::Unsatisfiable);
+    assert_eq!(range::parse("bytes=9-0", 100), Ranges::Full);
+    assert_eq!(range::parse("bytes=0-9, 9-0", 100), Ranges::Full);
+    assert_eq!(range::parse("bytes=a-b", 100), Ranges::Full);
+    assert_eq!(range::parse("items=0-9", 100), Ranges::Full);
+}
+
+#[test]
+fn deduplicate_uploads() {
+    let storage = storage::MemoryStorage::new();
+    let img = read(root().join("images").join("img.png")).expect("read img");
+    let store = |name: &str, opts: service::ThumbnailOptions| {
+        let img = service::Image::new(ImageKey::parse_name(name).expect("key"), img.clone());
+        let storage = &storage;
+        async move {
+            let stored = img
+                .store(storage, &opts, service::ConflictPolicy::Overwrite)
+                .await
+                .expect("store");
+            let deduplicated = stored.deduplicated;
+            stored.commit(storage).await.expect("commit");
+            deduplicated
+        }
+    };
+    let blobs =
+        ImageKey::parse(&format!(".blobs/{}/", hex::encode(Sha256::digest(&img)))).expect("key");
+    let quality = service::ThumbnailOptions {
+        quality: Some(42),
+        ..Default::default()
+    };
+
+    Runtime::new().expect("make runtime").block_on(async {
+        assert!(!store("a.jpeg", Default::default()).await);
+        assert!(store("b.jpeg", Default::default()).await);
+        assert_eq!(
+            storage
+                .get(&ImageKey::parse_name("b.jpeg").expect("key"))
+                .await
+                .expect("get"),
+            read(root().join("images/img_thumb.jpeg")).expect("read img")
+        );
+
+        assert!(!store("a.jpeg", quality.clone()).await);
+        let list = storage.list(&blobs, None, 10).await.expect("list");
+        assert_eq!(list.len(), 4);
+
+        assert!(store("b.jpeg", quality.clone()).await);
+        let list = storage.list(&blobs, None, 10).await.expect("list");
+        assert_eq!(list.len(), 3);
+
+        // Concurrent overwrites of a name don't leak references.
+        let overwrites = (0..4).map(|i| match i % 2 {
+            0 => store("a.jpeg", Default::default()),
+            _ => store("a.jpeg", quality.clone()),
+        });
+        futures::future::join_all(overwrites).await;
+
+        for name in &["a.jpeg", "b.jpeg"] {
+            let key = ImageKey::parse_name(name).expect("key");
+            service::Image::delete(&key, &storage)
+                .await
+                .expect("delete");
+        }
+        let list = storage.list(&blobs, None, 10).await.expect("list");
+        assert!(list.is_empty());
+    });
+}
+
+#[test]
+fn image_keys() {
+    assert!(ImageKey::parse_name("img.jpeg").is_ok());
+    for name in &["", "..", ".meta", "a/b", "a\\b", "a\0b"] {
+        assert!(ImageKey::parse_name(name).is_err(), "{:?}", name);
+    }
+
+    assert!(ImageKey::parse(".cache/img.jpeg/").is_ok());
+    for key in &["/etc/passwd", "a//b", "a/../b", "./a", "a\\b"] {
+        assert!(ImageKey::parse(key).is_err(), "{:?}", key);
+    }
+}
+
+#[test]
+fn memory_storage() {
+    check_storage(&storage::MemoryStorage::new(), "test");
+}
+
+#[test]
+fn fs_storage() {
+    let root = std::env::temp_dir().join(format!("img-storage-{}", std::process::id()));
+    let storage = storage::FsStorage::new(root.clone());
+    check_storage(&storage, "test");
+
+    // Failed writes don't leave their temporary files behind.
+    let file = ImageKey::parse("test/file").expect("key");
+    let nested = ImageKey::parse("test/file/nested").expect("key");
+    Runtime::new().expect("make runtime").block_on(async {
+        storage.put(&file, b"data".to_vec()).await.expect("put");
+        assert!(storage.put(&nested, b"data".to_vec()).await.is_err());
+        assert!(storage.copy(&file, &nested).await.is_err());
+    });
+    let tmp_files = std::fs::read_dir(root.join(".tmp")).expect("read .tmp");
+    assert_eq!(tmp_files.count(), 0);
+    std::fs::remove_dir_all(root).expect("remove test folder");
+}
+
+/// Needs an S3 compatible service with an existing bucket, e.g. a local MinIO.
+#[test]
+#[ignore]
+fn s3_storage() {
+    let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+    let cfg = config::S3Config {
+        endpoint: env("S3_ENDPOINT", "http://localhost:9000"),
+        bucket: env("S3_BUCKET", "img-storage-test"),
+        region: env("S3_REGION", "us-east-1"),
+        access_key: env("AWS_ACCESS_KEY_ID", "minioadmin"),
+        secret_key: env("AWS_SECRET_ACCESS_KEY", "minioadmin"),
+    };
+    let root = format!("test-{}", std::process::id());
+    check_storage(&storage::S3Storage::new(&cfg), &root);
+}
+
+#[cfg(test)]
+fn check_storage(storage: &dyn Storage, root: &str) {
+    let key = |k: &str| ImageKey::parse(&format!("{}/{}", root, k)).expect("key");
+    let keys =
+        |objects: Vec<storage::Object>| objects.into_iter().map(|o| o.key).collect::<Vec<_>>();
+
+    Runtime::new().expect("make runtime").block_on(async {
+        for k in &["a/1", "a/2", "a/b/3", "c"] {
+            storage
+                .put(&key(k), k.as_bytes().to_vec())
+                .await
+                .expect("put");
+        }
+
+        assert_eq!(storage.get(&key("a/1")).await.expect("get"), b"a/1");
+        assert_eq!(storage.head(&key("a/b/3")).await.expect("head").size, 5);
+
+        let big: Vec<u8> = (0..3 * storage::CHUNK_SIZE).map(|i| i as u8).collect();
+        storage.put(&key("big"), big.clone()).await.expect("put");
+        let read_big = |range: std::ops::Range<u64>| async move {
+            let chunks = storage.read(&key("big"), range).await.expect("read");
+            chunks.try_concat().await.expect("read chunks")
+        };
+        assert_eq!(read_big(0..big.len() as u64).await, big);
+        assert_eq!(read_big(100..70_000).await, &big[100..70_000]);
+        assert_eq!(read_big(1000..1000).await, b"");
+        assert_eq!(
+            read_big(big.len() as u64 - 5..1 << 20).await,
+            &big[big.len() - 5..]
+        );
+        storage.delete(&key("big")).await.expect("delete");
+        let err = storage
+            .read(&key("big"), 0..1)
+            .await
+            .err()
+            .expect("read deleted");
+        assert!(err.is_not_found());
+
+        let folder = key("a/");
+        let page = storage.list(&folder, None, 10).await.expect("list");
+        assert_eq!(keys(page), vec![key("a/1"), key("a/2")]);
+        let page = storage.list(&folder, None, 1).await.expect("list");
+        assert_eq!(keys(page), vec![key("a/1")]);
+        let page = storage
+            .list(&folder, Some(&key("a/1")), 10)
+            .await
+            .expect("list");
+        assert_eq!(keys(page), vec![key("a/2")]);
+
+        // Listings see the writes made after the folder was listed.
+        storage.copy(&key("a/1"), &key("a/0")).await.expect("copy");
+        storage.delete(&key("a/2")).await.expect("delete");
+        let page = storage.list(&folder, None, 10).await.expect("list");
+        assert_eq!(keys(page), vec![key("a/0"), key("a/1")]);
+        storage.put(&key("a/2"), b"2".to_vec()).await.expect("put");
+        storage.delete(&key("a/0")).await.expect("delete");
+
+        for k in &["a/1", "a/2", "a/b/3", "c"] {
+            storage.delete(&key(k)).await.expect("delete");
+        }
+
+        let err = storage.get(&key("a/1")).await.expect_err("get deleted");
+        assert!(err.is_not_found());
+        let err = storage
+            .delete(&key("a/1"))
+            .await
+            .expect_err("delete deleted");
+        assert!(err.is_not_found());
+    });
+}
+
 #[cfg(test)]
 fn store_json_img(port: u16, name: &str, data: api::ImageData) {
-    let img = api::ImageRequest {
-        filename: name.to_string(),
+    store_json_img_req(port, image_request(name, data))
+}
+
+#[cfg(test)]
+fn image_request(name: &str, data: api::ImageData) -> api::ImageRequest {
+    api::ImageRequest {
+        filename: Some(name.to_string()),
         data: data,
-    };
-    let req_body = api::StoreImgRequestBody(vec![img]);
-    let json = serde_json::ser::to_vec(&req_body).expect("serialize request");
+        id: Default::default(),
+        on_conflict: Default::default(),
+        thumbnail: Default::default(),
+        presets: Vec::new(),
+    }
+}
 
-    let resp = Client::new()
+#[cfg(test)]
+fn store_json_img_req(port: u16, img: api::ImageRequest) {
+    let name = img.filename.clone().expect("filename");
+    check_img_resp(&name, store_json(port, vec![img]));
+}
+
+#[cfg(test)]
+fn store_json(port: u16, imgs: Vec<api::ImageRequest>) -> reqwest::blocking::Response {
+    client()
         .post(&format!("http://localhost:{}/images", port))
         .header("Content-Type", "application/json")
-        .body(json)
+        .body(serde_json::ser::to_vec(&api::StoreImgRequestBody(imgs)).expect("json"))
         .send()
-        .expect("request");
-
-    check_img_resp(name, resp);
+        .expect("request")
 }
 
 #[cfg(test)]
@@ -84,9 +1651,13 @@ fn check_img_resp(name: &str, resp: reqwest::blocking::Response) {
     let resp_text = resp.text().expect("response text");
     let resp_body: api::StoreImgResponseBody =
         serde_json::de::from_str(&resp_text).expect("deserialize resp");
-    let img = api::ImageResponse::new(name.to_string());
-    let expected_resp_body = api::StoreImgResponseBody(vec![img]);
-    assert_eq!(resp_body, expected_resp_body);
+    // Whether the test image was deduplicated depends on what other tests stored before.
+    let names: Vec<_> = resp_body
+        .0
+        .iter()
+        .map(|img| (img.status, img.filename.as_ref().map(String::as_str)))
+        .collect();
+    assert_eq!(names, vec![(api::StoreStatus::Created, Some(name))]);
 }
 
 #[cfg(test)]
@@ -96,6 +1667,48 @@ fn check_file(name: &str) {
     assert_eq!(expected, got);
 }
 
+#[cfg(test)]
+lazy_static! {
+    /// Thumbnails being made by libvips, and the most made at once so far.
+    static ref VIPS_LOAD: std::sync::Mutex<(usize, usize)> = std::sync::Mutex::new((0, 0));
+}
+
+/// Counts a thumbnail being made by libvips while alive, see `vips_bound`.
+#[cfg(test)]
+pub(crate) struct VipsLoad;
+
+#[cfg(test)]
+impl VipsLoad {
+    pub(crate) fn enter() -> Self {
+        let mut load = VIPS_LOAD.lock().expect("lock vips load");
+        load.0 += 1;
+        load.1 = load.1.max(load.0);
+        VipsLoad
+    }
+}
+
+#[cfg(test)]
+impl Drop for VipsLoad {
+    fn drop(&mut self) {
+        VIPS_LOAD.lock().expect("lock vips load").0 -= 1;
+    }
+}
+
+/// Key of the test config granting every scope.
+#[cfg(test)]
+const ADMIN_KEY: &str = "test-admin-key";
+
+/// Client passing `ADMIN_KEY`.
+#[cfg(test)]
+fn client() -> Client {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("X-Api-Key", ADMIN_KEY.parse().expect("header value"));
+    Client::builder()
+        .default_headers(headers)
+        .build()
+        .expect("build client")
+}
+
 #[cfg(test)]
 fn new_server(port: u16) -> Runtime {
     let rt = Runtime::new().expect("make runtime");
@@ -107,7 +1720,142 @@ fn new_server(port: u16) -> Runtime {
     rt
 }
 
+/// Stand-in for webhook endpoints, recording the path, headers and body of every request.
+/// `/fail` answers with a server error.
+#[cfg(test)]
+fn new_receiver(port: u16) -> (Runtime, &'static std::sync::Mutex<Vec<Received>>) {
+    use std::sync::Mutex;
+
+    async fn respond(
+        req: hyper::Request<hyper::Body>,
+        received: &Mutex<Vec<Received>>,
+    ) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+        let path = req.uri().path().to_string();
+        let headers = req.headers().clone();
+        let body = req
+            .into_body()
+            .try_fold(Vec::new(), |mut body, chunk| async move {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .await?;
+        let status = match path.as_str() {
+            "/fail" => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+            _ => hyper::StatusCode::OK,
+        };
+        received
+            .lock()
+            .expect("lock received")
+            .push((path, headers, body));
+
+        let mut resp = hyper::Response::new(hyper::Body::empty());
+        *resp.status_mut() = status;
+        Ok(resp)
+    }
+
+    // Every receiver records its own requests, the test config sends events to one of them.
+    let received: &'static Mutex<Vec<Received>> = Box::leak(Box::new(Mutex::new(Vec::new())));
+    let rt = Runtime::new().expect("make runtime");
+    rt.spawn(async move {
+        let addr = ([127, 0, 0, 1], port).into();
+        let svc = make_service_fn(move |_| async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| respond(req, received)))
+        });
+        hyper::Server::bind(&addr).serve(svc).await.expect("server");
+    });
+    (rt, received)
+}
+
+/// Path, headers and body of a request to `new_receiver`.
+#[cfg(test)]
+type Received = (String, hyper::HeaderMap, Vec<u8>);
+
+/// Stand-in for remote image sources.
+#[cfg(test)]
+fn new_remote(port: u16) -> Runtime {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static FLAKY: AtomicUsize = AtomicUsize::new(0);
+
+    async fn respond(
+        req: hyper::Request<hyper::Body>,
+    ) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+        let mut resp = hyper::Response::builder();
+        let path = req.uri().path().to_string();
+        let body = match path.as_str() {
+            "/flaky" if FLAKY.fetch_add(1, Ordering::SeqCst) % 2 == 0 => {
+                resp.status(hyper::StatusCode::SERVICE_UNAVAILABLE);
+                Vec::new()
+            }
+            "/img.png" | "/flaky" => {
+                resp.header("Content-Type", "image/png");
+                read(root().join("images").join("img.png")).expect("read img")
+            }
+            "/text" => {
+                resp.header("Content-Type", "text/plain");
+                b"not an image".to_vec()
+            }
+            "/denied" => {
+                let location = format!("http://denied.localhost:{}/img.png", port_of(&req));
+                resp.status(hyper::StatusCode::FOUND)
+                    .header("Location", location.as_str());
+                Vec::new()
+            }
+            "/slow" => {
+                tokio::timer::delay_for(std::time::Duration::from_secs(3)).await;
+                resp.header("Content-Type", "image/png");
+                Vec::new()
+            }
+            p if p.starts_with("/redirect/") => {
+                let n: u32 = p["/redirect/".len()..].parse().expect("redirect count");
+                let location = match n {
+                    0 => "/img.png".to_string(),
+                    n => format!("/redirect/{}", n - 1),
+                };
+                resp.status(hyper::StatusCode::FOUND)
+                    .header("Location", location.as_str());
+                Vec::new()
+            }
+            _ => {
+                resp.status(hyper::StatusCode::NOT_FOUND);
+                Vec::new()
+            }
+        };
+
+        Ok(resp.body(hyper::Body::from(body)).expect("build response"))
+    }
+
+    fn port_of(req: &hyper::Request<hyper::Body>) -> String {
+        let host = req.headers()["Host"].to_str().expect("host");
+        host.rsplit(':').next().expect("port").to_string()
+    }
+
+    let rt = Runtime::new().expect("make runtime");
+    rt.spawn(async move {
+        let addr = ([127, 0, 0, 1], port).into();
+        let svc = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(respond)) });
+        hyper::Server::bind(&addr).serve(svc).await.expect("server");
+    });
+    rt
+}
+
 #[cfg(test)]
 fn root<'a>() -> &'a Path {
     Path::new(env!("CARGO_MANIFEST_DIR"))
 }
+
+/// CRC-32 of PNG chunks.
+#[cfg(test)]
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
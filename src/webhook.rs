@@ -0,0 +1,225 @@
+use super::config::{WebhookConfig, WebhookEndpoint, CONFIG};
+use super::key::{self, ImageKey};
+use super::limit;
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, USER_AGENT};
+use reqwest::RedirectPolicy;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::timer::{delay_for, Timeout};
+
+/// Deliveries started by `notify` and not done yet.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Shared by all deliveries, so connections to the endpoints are pooled.
+    static ref CLIENT: reqwest::Client = new_client();
+    /// Serializes appends to the dead letter log.
+    static ref DEAD_LETTERS: Mutex<()> = Mutex::new(());
+}
+
+/// Builds the delivery client. Endpoints are configured, not supplied by clients, so unlike
+/// remote fetches they aren't checked, but redirects aren't followed either.
+fn new_client() -> reqwest::Client {
+    let mut headers = HeaderMap::new();
+    let user_agent =
+        HeaderValue::from_str(&CONFIG.http_client.user_agent).expect("valid user agent");
+    headers.insert(USER_AGENT, user_agent);
+
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .redirect(RedirectPolicy::none())
+        .build()
+        .expect("build http client")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) enum EventKind {
+    #[serde(rename = "image.created")]
+    Created,
+    /// An image was stored under the name of an existing one.
+    #[serde(rename = "image.replaced")]
+    Replaced,
+    #[serde(rename = "image.deleted")]
+    Deleted,
+}
+
+/// JSON body of webhook requests.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Event {
+    /// Unique ID, the same for every attempt to deliver the event.
+    pub(crate) id: String,
+    #[serde(rename = "type")]
+    pub(crate) kind: EventKind,
+    pub(crate) filename: String,
+    pub(crate) occurred_at: String,
+}
+
+impl Event {
+    pub(crate) fn new(kind: EventKind, key: &ImageKey) -> Self {
+        Event {
+            id: key::ulid(),
+            kind: kind,
+            filename: key.to_string(),
+            occurred_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+}
+
+/// Line of the dead letter log.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct DeadLetter {
+    pub(crate) endpoint: String,
+    pub(crate) event: Event,
+    pub(crate) attempts: u32,
+    /// Why the last attempt failed.
+    pub(crate) error: String,
+}
+
+/// Sends the event to the subscribed endpoints in the background.
+pub(crate) fn notify(event: Event) {
+    let cfg = &CONFIG.webhooks;
+    for endpoint in cfg.endpoints.iter().filter(|e| e.subscribes(event.kind)) {
+        let event = event.clone();
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            let _ = deliver(cfg, endpoint, &event, &CONFIG.dead_letter_log()).await;
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Waits for the deliveries started so far, so a graceful shutdown doesn't drop events. It ends
+/// at the latest once the last one ran out of retries.
+pub(crate) async fn drain() {
+    limit::wait_until_done("webhook deliveries", || PENDING.load(Ordering::SeqCst)).await
+}
+
+/// Posts the event to the endpoint, retrying with exponential backoff. An event that never gets
+/// through is recorded in the dead letter log.
+pub(crate) async fn deliver(
+    cfg: &WebhookConfig,
+    endpoint: &WebhookEndpoint,
+    event: &Event,
+    dead_letters: &Path,
+) -> Result<(), Error> {
+    let body = serde_json::to_vec(event).expect("serialize event");
+    let mut attempt = 0;
+    loop {
+        let err = match send(cfg, endpoint, event, &body).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        if attempt == cfg.retries {
+            error!("webhook {} to {}: {}", event.id, endpoint.url, err);
+            let letter = DeadLetter {
+                endpoint: endpoint.url.clone(),
+                event: event.clone(),
+                attempts: attempt + 1,
+                error: err.to_string(),
+            };
+            if let Err(err) = dead_letter(dead_letters.to_path_buf(), letter).await {
+                error!("record dead letter in {}: {}", dead_letters.display(), err);
+            }
+            return Err(err);
+        }
+
+        warn!(
+            "webhook {} to {}, retrying: {}",
+            event.id, endpoint.url, err
+        );
+        delay_for(limit::backoff(cfg.retry_backoff_ms, attempt)).await;
+        attempt += 1;
+    }
+}
+
+async fn send(
+    cfg: &WebhookConfig,
+    endpoint: &WebhookEndpoint,
+    event: &Event,
+    body: &[u8],
+) -> Result<(), Error> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after epoch")
+        .as_secs()
+        .to_string();
+    let req = CLIENT
+        .post(endpoint.url.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Id", event.id.as_str())
+        .header("X-Webhook-Timestamp", timestamp.as_str())
+        .header(
+            "X-Webhook-Signature",
+            signature(&endpoint.secret, &timestamp, body).as_str(),
+        )
+        .body(body.to_vec())
+        .send();
+
+    let resp = Timeout::new(req, Duration::from_secs(cfg.timeout_secs))
+        .await
+        .map_err(|_| Error::Timeout)??;
+    if !resp.status().is_success() {
+        return Err(Error::Status(resp.status().as_u16()));
+    }
+
+    Ok(())
+}
+
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of `{timestamp}.{body}`, so receivers can
+/// check where an event came from and reject replays of old ones.
+pub(crate) fn signature(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.input(timestamp.as_bytes());
+    mac.input(b".");
+    mac.input(body);
+    format!("sha256={}", hex::encode(mac.result().code()))
+}
+
+async fn dead_letter(path: PathBuf, letter: DeadLetter) -> std::io::Result<()> {
+    tokio_executor::blocking::run(move || {
+        let mut line = serde_json::to_vec(&letter).expect("serialize dead letter");
+        line.push(b'\n');
+
+        let _lock = DEAD_LETTERS.lock().expect("lock dead letters");
+        if let Some(folder) = path.parent() {
+            std::fs::create_dir_all(folder)?;
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?
+            .write_all(&line)
+    })
+    .await
+}
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    Status(u16),
+    Timeout,
+    Reqwest(reqwest::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Status(status) => write!(f, "endpoint responded with status {}", status),
+            Error::Timeout => write!(f, "timed out"),
+            Error::Reqwest(err) => write!(f, "failed to send: {}", err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Reqwest(err)
+    }
+}